@@ -0,0 +1,278 @@
+use fuse_mt::RequestInfo;
+
+use std::path::{Component, Path};
+
+use filesystem::in_group;
+use names::{decode_name, CaseMapping, RESERVED_NAMES};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    // Reading receive, messages.jsonl and the other files in a directory
+    Read,
+    // Writing to a channel's send file
+    Send,
+    // Writing to /send and the other files at the top level
+    Command,
+}
+
+impl Operation {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match &*s.to_lowercase() {
+            "read" => Some(Operation::Read),
+            "send" => Some(Operation::Send),
+            "command" => Some(Operation::Command),
+            _ => None,
+        }
+    }
+}
+
+// Grants the matching users some operations on some channels. A rule without
+// a uid or gid applies to everyone.
+#[derive(Clone, Debug)]
+pub struct AclRule {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // "*" matches every channel, and "/" the files at the top level
+    pub channels: Vec<String>,
+    pub operations: Vec<Operation>,
+}
+
+impl AclRule {
+    fn applies_to(&self, req: &RequestInfo) -> bool {
+        self.uid.map(|uid| uid == req.uid).unwrap_or(true)
+            && self.gid.map(|gid| in_group(gid, req)).unwrap_or(true)
+    }
+
+    // Channels are compared the way the server compares them
+    fn covers(&self, channel: Option<&str>, casemapping: CaseMapping) -> bool {
+        let channel = channel.map(|c| casemapping.fold(c)).unwrap_or("/".to_owned());
+
+        self.channels.iter().any(|c| c == "*" || casemapping.fold(c) == channel)
+    }
+}
+
+// Restricts what other users can do when the filesystem is mounted with
+// allow_other. This is checked in addition to the usual file permissions.
+// The user running ircfs (and root) can always do everything, and when no
+// rules are configured nobody else is restricted.
+pub struct Acl {
+    owner: u32,
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(owner: u32, rules: Vec<AclRule>) -> Self {
+        Acl {
+            owner: owner,
+            rules: rules,
+        }
+    }
+
+    pub fn allows(&self, req: &RequestInfo, channel: Option<&str>, operation: Operation, casemapping: CaseMapping) -> bool {
+        if self.rules.is_empty() || req.uid == 0 || req.uid == self.owner {
+            return true;
+        }
+
+        self.rules.iter().any(|rule| {
+            rule.applies_to(req)
+                && rule.covers(channel, casemapping)
+                && rule.operations.contains(&operation)
+        })
+    }
+}
+
+// The channel (or query) a path belongs to, or None for the top level
+// (including ircfs's own directories, like /isupport)
+pub fn channel_of(path: &Path, is_dir: bool) -> Option<String> {
+    let names = path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Reserved names are checked as they appear in the path: a channel
+    // called "send" has the directory "%73end"
+    if names.first().map(|name| RESERVED_NAMES.contains(&&**name)).unwrap_or(false) {
+        None
+    } else if names.len() > 1 || (names.len() == 1 && is_dir) {
+        Some(decode_name(&names[0]))
+    } else {
+        None
+    }
+}
+
+// Writing counts as a command at the top level, and as sending elsewhere
+pub fn write_operation(path: &Path) -> Operation {
+    if channel_of(path, false).is_some() {
+        Operation::Send
+    } else {
+        Operation::Command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    const OWNER: u32 = 1000;
+    const ASCII: CaseMapping = CaseMapping::Ascii;
+
+    // pid is this process, which the tests assume isn't in the groups below
+    fn req(uid: u32, gid: u32) -> RequestInfo {
+        RequestInfo { unique: 0, uid: uid, gid: gid, pid: process::id() }
+    }
+
+    fn rule(uid: Option<u32>, gid: Option<u32>, channels: &[&str], operations: &[Operation]) -> AclRule {
+        AclRule {
+            uid: uid,
+            gid: gid,
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            operations: operations.to_vec(),
+        }
+    }
+
+    #[test]
+    fn no_rules_restrict_nobody() {
+        let acl = Acl::new(OWNER, Vec::new());
+        assert!(acl.allows(&req(2000, 2000), Some("#secret"), Operation::Send, ASCII));
+        assert!(acl.allows(&req(2000, 2000), None, Operation::Command, ASCII));
+    }
+
+    #[test]
+    fn owner_and_root_are_never_restricted() {
+        let acl = Acl::new(OWNER, vec![rule(Some(2000), None, &["#a"], &[Operation::Read])]);
+        for uid in &[OWNER, 0] {
+            assert!(acl.allows(&req(*uid, 3000), Some("#b"), Operation::Send, ASCII));
+            assert!(acl.allows(&req(*uid, 3000), None, Operation::Command, ASCII));
+        }
+    }
+
+    #[test]
+    fn rules_match_users_and_groups() {
+        let acl = Acl::new(OWNER, vec![
+            rule(Some(2000), None, &["#users"], &[Operation::Read]),
+            rule(None, Some(3000), &["#group"], &[Operation::Read]),
+            rule(Some(2001), Some(3001), &["#both"], &[Operation::Read]),
+        ]);
+
+        assert!(acl.allows(&req(2000, 100), Some("#users"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2002, 100), Some("#users"), Operation::Read, ASCII));
+
+        assert!(acl.allows(&req(2002, 3000), Some("#group"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2002, 3002), Some("#group"), Operation::Read, ASCII));
+
+        // Both have to match
+        assert!(acl.allows(&req(2001, 3001), Some("#both"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2001, 100), Some("#both"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2000, 3001), Some("#both"), Operation::Read, ASCII));
+
+        // Rules only grant what they say
+        assert!(! acl.allows(&req(2000, 100), Some("#group"), Operation::Read, ASCII));
+    }
+
+    #[test]
+    fn rules_without_a_user_or_group_apply_to_everyone() {
+        let acl = Acl::new(OWNER, vec![rule(None, None, &["#public"], &[Operation::Read])]);
+        assert!(acl.allows(&req(2000, 100), Some("#public"), Operation::Read, ASCII));
+        assert!(acl.allows(&req(2001, 101), Some("#public"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2001, 101), Some("#private"), Operation::Read, ASCII));
+    }
+
+    #[test]
+    fn wildcard_and_top_level_channels() {
+        let acl = Acl::new(OWNER, vec![
+            rule(Some(2000), None, &["*"], &[Operation::Read]),
+            rule(Some(2001), None, &["/"], &[Operation::Read, Operation::Command]),
+        ]);
+
+        assert!(acl.allows(&req(2000, 100), Some("#a"), Operation::Read, ASCII));
+        assert!(acl.allows(&req(2000, 100), Some("bob"), Operation::Read, ASCII));
+        assert!(acl.allows(&req(2000, 100), None, Operation::Read, ASCII));
+
+        assert!(acl.allows(&req(2001, 100), None, Operation::Read, ASCII));
+        assert!(acl.allows(&req(2001, 100), None, Operation::Command, ASCII));
+        assert!(! acl.allows(&req(2001, 100), Some("#a"), Operation::Read, ASCII));
+    }
+
+    #[test]
+    fn channels_are_compared_with_the_casemapping() {
+        let acl = Acl::new(OWNER, vec![rule(Some(2000), None, &["#Foo[1]"], &[Operation::Read])]);
+        let allows = |channel: &str, casemapping: CaseMapping| {
+            acl.allows(&req(2000, 100), Some(channel), Operation::Read, casemapping)
+        };
+
+        assert!(allows("#foo[1]", CaseMapping::Ascii));
+        assert!(! allows("#foo{1}", CaseMapping::Ascii));
+        assert!(allows("#foo{1}", CaseMapping::Rfc1459));
+        assert!(allows("#FOO{1}", CaseMapping::StrictRfc1459));
+
+        // Only ASCII letters are folded
+        assert!(! allows("#fóo[1]", CaseMapping::Ascii));
+        let acl = Acl::new(OWNER, vec![rule(Some(2000), None, &["#Ä"], &[Operation::Read])]);
+        assert!(! acl.allows(&req(2000, 100), Some("#ä"), Operation::Read, CaseMapping::Rfc1459));
+    }
+
+    #[test]
+    fn operations_are_granted_separately() {
+        let acl = Acl::new(OWNER, vec![
+            rule(Some(2000), None, &["#a"], &[Operation::Read]),
+            rule(Some(2001), None, &["#a"], &[Operation::Send]),
+            rule(Some(2002), None, &["*", "/"], &[Operation::Command]),
+        ]);
+
+        assert!(acl.allows(&req(2000, 100), Some("#a"), Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2000, 100), Some("#a"), Operation::Send, ASCII));
+
+        assert!(acl.allows(&req(2001, 100), Some("#a"), Operation::Send, ASCII));
+        assert!(! acl.allows(&req(2001, 100), Some("#a"), Operation::Read, ASCII));
+
+        assert!(acl.allows(&req(2002, 100), None, Operation::Command, ASCII));
+        assert!(! acl.allows(&req(2002, 100), None, Operation::Read, ASCII));
+        assert!(! acl.allows(&req(2002, 100), Some("#a"), Operation::Send, ASCII));
+    }
+
+    #[test]
+    fn operation_names() {
+        assert_eq!(Operation::from_str("read"), Some(Operation::Read));
+        assert_eq!(Operation::from_str("Send"), Some(Operation::Send));
+        assert_eq!(Operation::from_str("COMMAND"), Some(Operation::Command));
+        assert_eq!(Operation::from_str("write"), None);
+    }
+
+    #[test]
+    fn paths_belong_to_channels() {
+        let channel = |path: &str, is_dir: bool| channel_of(Path::new(path), is_dir);
+
+        assert_eq!(channel("/", true), None);
+        assert_eq!(channel("/send", false), None);
+        assert_eq!(channel("/receive", false), None);
+        assert_eq!(channel("/whois", true), None);
+        assert_eq!(channel("/whois/alice", false), None);
+        assert_eq!(channel("/isupport/CHANTYPES", false), None);
+
+        assert_eq!(channel("/#a", true), Some("#a".to_owned()));
+        assert_eq!(channel("/#a/send", false), Some("#a".to_owned()));
+        assert_eq!(channel("/bob/receive", false), Some("bob".to_owned()));
+        assert_eq!(channel("/#a%2Fb/send", false), Some("#a/b".to_owned()));
+
+        // A lone file at the top level isn't a channel
+        assert_eq!(channel("/#a", false), None);
+
+        // Channels named like ircfs's own files are escaped, and still channels
+        assert_eq!(channel("/%73end", true), Some("send".to_owned()));
+        assert_eq!(channel("/%73end/send", false), Some("send".to_owned()));
+        assert_eq!(channel("/%77hois/receive", false), Some("whois".to_owned()));
+    }
+
+    #[test]
+    fn writes_are_commands_or_sends() {
+        assert_eq!(write_operation(Path::new("/send")), Operation::Command);
+        assert_eq!(write_operation(Path::new("/queue")), Operation::Command);
+        assert_eq!(write_operation(Path::new("/#a/send")), Operation::Send);
+        assert_eq!(write_operation(Path::new("/bob/send")), Operation::Send);
+        assert_eq!(write_operation(Path::new("/%73end/send")), Operation::Send);
+    }
+}
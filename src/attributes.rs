@@ -0,0 +1,122 @@
+use toml;
+
+use std::collections::BTreeMap;
+use std::env::var_os;
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Attributes changed with chmod/chown, which are reapplied to files as they're
+// created so that they survive restarts
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct SavedAttr {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AttributeFile {
+    #[serde(default)]
+    paths: BTreeMap<String, SavedAttr>,
+}
+
+pub struct AttributeStore {
+    file: Option<PathBuf>,
+    paths: BTreeMap<String, SavedAttr>,
+    // Counts changes, so that an older snapshot is never saved over a newer one
+    generation: u64,
+    saved: Arc<Mutex<u64>>,
+}
+
+// The store's contents as of one change, to be written out once the
+// filesystem is no longer locked
+pub struct AttributeSnapshot {
+    file: Option<PathBuf>,
+    paths: BTreeMap<String, SavedAttr>,
+    generation: u64,
+    saved: Arc<Mutex<u64>>,
+}
+
+impl AttributeStore {
+    // A store that isn't saved anywhere
+    pub fn new() -> Self {
+        AttributeStore {
+            file: None,
+            paths: BTreeMap::new(),
+            generation: 0,
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn load(file: PathBuf) -> io::Result<Self> {
+        let paths = match File::open(&file) {
+            Ok(mut f) => {
+                let mut buf = String::new();
+                f.read_to_string(&mut buf)?;
+                let contents: AttributeFile = toml::from_str(&buf)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                contents.paths
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(AttributeStore {
+            file: Some(file),
+            paths: paths,
+            generation: 0,
+            saved: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<SavedAttr> {
+        self.paths.get(&*path.as_ref().to_string_lossy()).cloned()
+    }
+
+    pub fn update<P: AsRef<Path>, F: FnOnce(&mut SavedAttr)>(&mut self, path: P, f: F) -> AttributeSnapshot {
+        f(self.paths.entry(path.as_ref().to_string_lossy().into_owned()).or_insert_with(SavedAttr::default));
+        self.generation += 1;
+
+        AttributeSnapshot {
+            file: self.file.clone(),
+            paths: self.paths.clone(),
+            generation: self.generation,
+            saved: self.saved.clone(),
+        }
+    }
+}
+
+impl AttributeSnapshot {
+    pub fn save(self) -> io::Result<()> {
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return Ok(()),
+        };
+
+        let mut saved = self.saved.lock().unwrap();
+        if *saved >= self.generation {
+            return Ok(());
+        }
+
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let contents = toml::to_string(&AttributeFile { paths: self.paths.clone() })
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        File::create(file)?.write_all(contents.as_bytes())?;
+
+        *saved = self.generation;
+        Ok(())
+    }
+}
+
+// $XDG_DATA_HOME/ircfs/SERVER.toml, or ~/.local/share/ircfs/SERVER.toml
+pub fn default_attributes_file(server: &str) -> Option<PathBuf> {
+    let data_dir = var_os("XDG_DATA_HOME").map(PathBuf::from)
+        .or(var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")));
+
+    data_dir.map(|dir| dir.join("ircfs").join(format!("{}.toml", server.replace('/', "_"))))
+}
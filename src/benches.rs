@@ -0,0 +1,131 @@
+// Benchmarks for lock contention in the filesystem. They're ignored by
+// default; run them with
+//
+//     cargo test --release benches -- --ignored --nocapture
+//
+// Each one has a few threads reading one channel's receive file while another
+// thread keeps writing to a different channel's send file, which records the
+// line and hands it to the network, and counts how many of each get done.
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use attributes::AttributeStore;
+use filesystem::*;
+
+const READERS: usize = 4;
+const DURATION_MS: u64 = 2000;
+const LINE: &'static [u8] = b"12:00:00 nick: a line of chat about as long as they usually are\n";
+
+// Keeps the written file from growing without bound
+const APPENDS_PER_TRUNCATE: usize = 10000;
+
+// Roughly how long handing a line to a busy connection can take
+const SEND_TIME_US: u64 = 200;
+
+fn filesystem() -> Arc<RwLock<Filesystem>> {
+    let mut fs = Filesystem::new(1000, 1000, 0o600, 0o700, AttributeStore::new());
+    for dir in &["/#read", "/#write"] {
+        fs.mk_dir(dir).unwrap();
+        fs.mk_ro_file(format!("{}/receive", dir)).unwrap();
+        fs.mk_rw_file(format!("{}/send", dir)).unwrap();
+    }
+
+    if let Some(&Node::F(ref file)) = fs.get("/#read/receive") {
+        for _ in 0..APPENDS_PER_TRUNCATE {
+            file.insert_data(LINE);
+        }
+    }
+
+    Arc::new(RwLock::new(fs))
+}
+
+// Like shared_file in ircfs.rs: only the tree's read lock is needed to get
+// hold of a file
+fn file(fs: &RwLock<Filesystem>, path: &str) -> FuseFile {
+    match fs.read().unwrap().get(path) {
+        Some(&Node::F(ref file)) => file.clone(),
+        _ => panic!("{} is missing", path),
+    }
+}
+
+fn send() {
+    thread::sleep(Duration::from_micros(SEND_TIME_US));
+}
+
+// Reads and writes per second
+fn reads_while_writing<F>(write: F) -> (u64, u64)
+    where F: Fn(&RwLock<Filesystem>) + Send + 'static
+{
+    let fs = filesystem();
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicUsize::new(0));
+
+    let writer = {
+        let fs = fs.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut writes = 0;
+            while ! done.load(Ordering::Relaxed) {
+                write(&fs);
+                writes += 1;
+                if writes % APPENDS_PER_TRUNCATE == 0 {
+                    file(&fs, "/#write/send").truncate(0);
+                }
+            }
+            writes as u64
+        })
+    };
+
+    let readers = (0..READERS).map(|_| {
+        let fs = fs.clone();
+        let done = done.clone();
+        let reads = reads.clone();
+        thread::spawn(move || {
+            // Reading the whole file in 4 KiB blocks, over and over
+            let mut offset = 0;
+            while ! done.load(Ordering::Relaxed) {
+                let data = file(&fs, "/#read/receive").read_at(offset, 4096);
+                offset = if data.is_empty() { 0 } else { offset + data.len() as u64 };
+                reads.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    thread::sleep(Duration::from_millis(DURATION_MS));
+    done.store(true, Ordering::Relaxed);
+
+    let writes = writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let reads = reads.load(Ordering::Relaxed) as u64;
+    (reads * 1000 / DURATION_MS, writes * 1000 / DURATION_MS)
+}
+
+#[test]
+#[ignore]
+fn reads_while_another_channel_is_written_to() {
+    // How writes used to work, with the whole tree locked throughout
+    let tree_locked = reads_while_writing(|fs| {
+        let fs = fs.write().unwrap();
+        if let Some(&Node::F(ref file)) = fs.get("/#write/send") {
+            file.insert_data(LINE);
+        }
+        send();
+    });
+
+    // How they work now: only the file is locked, and only while it's
+    // being appended to
+    let file_locked = reads_while_writing(|fs| {
+        file(fs, "/#write/send").insert_data(LINE);
+        send();
+    });
+
+    println!();
+    println!("writing under the tree's write lock: {} reads/s, {} writes/s", tree_locked.0, tree_locked.1);
+    println!("writing under the file's own lock:   {} reads/s, {} writes/s", file_locked.0, file_locked.1);
+}
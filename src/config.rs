@@ -1,9 +1,17 @@
 extern crate irc;
 use irc::client::prelude::Config;
 
+use libc;
+
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::PathBuf;
+
+use acl::{AclRule, Operation};
+use permissions::Mode;
+use proxy::ProxyKind;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub owners: Option<Vec<String>>,
     pub nickname: Option<String>,
@@ -30,6 +38,64 @@ pub struct ServerConfig {
     pub options: Option<HashMap<String, String>>,
     pub burst_window_length: Option<u32>,
     pub max_messages_in_burst: Option<u32>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_bundle: Option<String>,
+    pub cert_fingerprint: Option<String>,
+    pub tls_insecure: Option<bool>,
+    pub proxy_type: Option<String>,
+    pub proxy_host: Option<String>,
+    pub proxy_port: Option<u16>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    pub truncate_receive: Option<bool>,
+    pub default_file_mode: Option<String>,
+    pub default_dir_mode: Option<String>,
+    pub group: Option<String>,
+    pub attributes_file: Option<String>,
+    pub allow_other: Option<bool>,
+    pub acl: Option<Vec<AclEntry>>,
+}
+
+// An [[acl]] table in the config file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AclEntry {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub channels: Option<Vec<String>>,
+    pub operations: Option<Vec<String>>,
+}
+
+// Settings used by ircfs itself, rather than passed on to the irc crate
+#[derive(Clone, Debug, Default)]
+pub struct FsConfig {
+    pub tls: TlsConfig,
+    pub proxy: Option<ProxyConfig>,
+    pub truncate_receive: bool,
+    pub file_mode: u16,
+    pub dir_mode: u16,
+    pub group: Option<u32>,
+    pub attributes_file: Option<PathBuf>,
+    pub allow_other: bool,
+    pub acl: Vec<AclRule>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_bundle: Option<String>,
+    pub fingerprint: Option<String>,
+    pub insecure: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
 }
 
 pub fn convert_config(config: ServerConfig) -> Config {
@@ -65,3 +131,118 @@ pub fn convert_config(config: ServerConfig) -> Config {
         max_messages_in_burst: config.max_messages_in_burst,
     }
 }
+
+pub fn fs_config(config: &ServerConfig) -> Result<FsConfig, String> {
+    let config = config.clone();
+
+    let proxy = match config.proxy_host {
+        Some(host) => {
+            let kind = match config.proxy_type {
+                Some(ref kind) => ProxyKind::from_str(kind)
+                    .ok_or(format!("unknown proxy type \"{}\"", kind))?,
+                None => ProxyKind::Socks5,
+            };
+            let port = match kind {
+                ProxyKind::Socks5 => config.proxy_port.unwrap_or(1080),
+                ProxyKind::Http => config.proxy_port.unwrap_or(8080),
+            };
+
+            Some(ProxyConfig {
+                kind: kind,
+                host: host,
+                port: port,
+                username: config.proxy_username,
+                password: config.proxy_password,
+            })
+        },
+        None => None,
+    };
+
+    let mut acl = Vec::new();
+    for entry in config.acl.unwrap_or_default() {
+        let mut operations = Vec::new();
+        for operation in entry.operations.unwrap_or_default() {
+            operations.push(Operation::from_str(&operation)
+                .ok_or(format!("unknown ACL operation \"{}\"", operation))?);
+        }
+
+        acl.push(AclRule {
+            uid: match entry.user {
+                Some(ref user) => Some(lookup_user(user)?),
+                None => None,
+            },
+            gid: match entry.group {
+                Some(ref group) => Some(lookup_group(group)?),
+                None => None,
+            },
+            channels: entry.channels.unwrap_or_default(),
+            operations: operations,
+        });
+    }
+
+    Ok(FsConfig {
+        tls: TlsConfig {
+            client_cert: config.client_cert,
+            client_key: config.client_key,
+            ca_bundle: config.ca_bundle,
+            fingerprint: config.cert_fingerprint,
+            insecure: config.tls_insecure.unwrap_or(false),
+        },
+        proxy: proxy,
+        truncate_receive: config.truncate_receive.unwrap_or(false),
+        file_mode: match config.default_file_mode {
+            Some(ref mode) => parse_mode(mode)?,
+            None => 0o600,
+        },
+        dir_mode: match config.default_dir_mode {
+            Some(ref mode) => parse_mode(mode)?,
+            None => 0o700,
+        },
+        group: match config.group {
+            Some(ref group) => Some(lookup_group(group)?),
+            None => None,
+        },
+        attributes_file: config.attributes_file.map(PathBuf::from),
+        allow_other: config.allow_other.unwrap_or(false),
+        acl: acl,
+    })
+}
+
+// Modes are written in octal, e.g. "640" or "0o640"
+fn parse_mode(mode: &str) -> Result<u16, String> {
+    let digits = mode.trim_start_matches("0o");
+    u16::from_str_radix(digits, 8).ok()
+        .and_then(|mode| Mode::new(mode).ok())
+        .map(|mode| mode.as_int())
+        .ok_or(format!("invalid mode \"{}\"", mode))
+}
+
+// Groups can be given by name or by number
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| format!("invalid group \"{}\"", group))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        Err(format!("unknown group \"{}\"", group))
+    } else {
+        Ok(unsafe { (*entry).gr_gid })
+    }
+}
+
+// Users can be given by name or by number
+fn lookup_user(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Ok(uid);
+    }
+
+    let name = CString::new(user).map_err(|_| format!("invalid user \"{}\"", user))?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        Err(format!("unknown user \"{}\"", user))
+    } else {
+        Ok(unsafe { (*entry).pw_uid })
+    }
+}
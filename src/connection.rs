@@ -0,0 +1,484 @@
+use irc::client::prelude::Config;
+
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::ssl::{SslConnectorBuilder, SslMethod, SslRef, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::x509::{X509, X509_FILETYPE_PEM};
+
+use libc;
+
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::Duration;
+
+use config::{FsConfig, TlsConfig};
+use proxy;
+
+// How long a read from the server over TLS may block. Readable data on the
+// socket is usually a whole record, but can be part of one, or a record with
+// no application data in it.
+const TLS_READ_TIMEOUT: u64 = 50;
+
+// When TLS or a proxy is used, the connection to the IRC server is made by
+// ircfs itself rather than by the irc crate, so that we have control over
+// TLS and can go through a proxy. The irc crate is then pointed at a
+// plaintext relay on localhost. Plain connections are left to the irc crate.
+pub struct Connection {
+    local_port: Option<u16>,
+    status: String,
+}
+
+impl Connection {
+    pub fn open(config: &Config, fs_config: &FsConfig) -> io::Result<Self> {
+        let use_ssl = config.use_ssl.unwrap_or(false);
+        let host = config.server.clone()
+            .ok_or(Error::new(ErrorKind::InvalidInput, "no server specified"))?;
+        let port = config.port.unwrap_or(if use_ssl { 6697 } else { 6667 });
+
+        let mut status = format!("server: {}:{}\n", host, port);
+
+        if !use_ssl && fs_config.proxy.is_none() {
+            status.push_str("tls: no\n");
+            return Ok(Connection {
+                local_port: None,
+                status: status,
+            });
+        }
+
+        let stream = match fs_config.proxy {
+            Some(ref proxy) => {
+                status.push_str(&format!("proxy: {} {}:{}\n", proxy.kind, proxy.host, proxy.port));
+                proxy::connect(proxy, &host, port)?
+            },
+            None => TcpStream::connect((host.as_str(), port))?,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let local_port = listener.local_addr()?.port();
+
+        if use_ssl {
+            let stream = connect_tls(&host, stream, &fs_config.tls)?;
+            // Only set after the handshake, which needs blocking reads
+            stream.get_ref().set_read_timeout(Some(Duration::from_millis(TLS_READ_TIMEOUT)))?;
+            status.push_str(&tls_status(stream.ssl(), &fs_config.tls));
+            thread::spawn(move || accept_and_relay(listener, stream));
+        } else {
+            status.push_str("tls: no\n");
+            thread::spawn(move || accept_and_relay(listener, stream));
+        }
+
+        Ok(Connection {
+            local_port: Some(local_port),
+            status: status,
+        })
+    }
+
+    // The port of the relay the irc crate should connect to, if any
+    pub fn local_port(&self) -> Option<u16> {
+        self.local_port
+    }
+
+    // Contents of the "connection" status file
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+fn connect_tls(host: &str, stream: TcpStream, tls: &TlsConfig)
+-> io::Result<::openssl::ssl::SslStream<TcpStream>> {
+    let mut builder = SslConnectorBuilder::new(SslMethod::tls()).map_err(tls_error)?;
+
+    // Either we don't verify at all, or the pinned fingerprint takes the
+    // place of the usual chain-of-trust verification
+    let verify = !tls.insecure && tls.fingerprint.is_none();
+
+    {
+        let context = builder.builder_mut();
+
+        // The connector always loads the system CA store, and a CA file can
+        // only be added to it. So as well as passing verification, the chain
+        // has to include one of the bundle's certificates.
+        if let Some(ref path) = tls.ca_bundle {
+            context.set_ca_file(path).map_err(tls_error)?;
+
+            let mut pem = Vec::new();
+            File::open(path).and_then(|mut file| file.read_to_end(&mut pem))?;
+            let trusted = X509::stack_from_pem(&pem).map_err(tls_error)?
+                .iter()
+                .map(|cert| cert.fingerprint(MessageDigest::sha256()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(tls_error)?;
+
+            if verify {
+                context.set_verify_callback(SSL_VERIFY_PEER, move |preverified, store| {
+                    preverified && store.chain().map(|chain| {
+                        chain.iter().any(|cert| {
+                            cert.fingerprint(MessageDigest::sha256())
+                                .map(|fingerprint| trusted.contains(&fingerprint))
+                                .unwrap_or(false)
+                        })
+                    }).unwrap_or(false)
+                });
+            }
+        }
+
+        if let Some(ref path) = tls.client_cert {
+            context.set_certificate_chain_file(path).map_err(tls_error)?;
+            let key = tls.client_key.as_ref().unwrap_or(path);
+            context.set_private_key_file(key, X509_FILETYPE_PEM).map_err(tls_error)?;
+            context.check_private_key().map_err(tls_error)?;
+        }
+
+        if !verify {
+            context.set_verify(SSL_VERIFY_NONE);
+        }
+    }
+
+    let connector = builder.build();
+    let stream = if verify {
+        connector.connect(host, stream)
+    } else {
+        // Skip verification, but still send SNI so that servers sharing an
+        // address present the right certificate
+        let mut configuration = connector.configure().map_err(tls_error)?;
+        if host.parse::<IpAddr>().is_err() {
+            configuration.set_hostname(host).map_err(tls_error)?;
+        }
+        configuration.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+    }.map_err(|e| Error::new(ErrorKind::Other, format!("TLS handshake failed: {}", e)))?;
+
+    if let Some(ref pinned) = tls.fingerprint {
+        let actual = peer_fingerprint(stream.ssl());
+        if actual.as_ref().map(|f| normalize_fingerprint(f)) != Some(normalize_fingerprint(pinned)) {
+            return Err(Error::new(ErrorKind::Other,
+                format!("certificate fingerprint mismatch: expected {}, got {}",
+                    pinned,
+                    actual.unwrap_or("none".to_owned()),
+                )
+            ));
+        }
+    }
+
+    Ok(stream)
+}
+
+fn tls_status(ssl: &SslRef, tls: &TlsConfig) -> String {
+    let mut status = String::from("tls: yes\n");
+
+    status.push_str(&format!("protocol: {}\n", ssl.version()));
+    if let Some(cipher) = ssl.current_cipher() {
+        status.push_str(&format!("cipher: {}\n", cipher.name()));
+    }
+
+    if let Some(cert) = ssl.peer_certificate() {
+        let common_name = cert.subject_name()
+            .entries_by_nid(nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok().map(|cn| cn.to_string()));
+        if let Some(cn) = common_name {
+            status.push_str(&format!("peer certificate: {}\n", cn));
+        }
+    }
+    if let Some(fingerprint) = peer_fingerprint(ssl) {
+        status.push_str(&format!("peer fingerprint (sha256): {}\n", fingerprint));
+    }
+
+    let verification = if tls.insecure {
+        "none (insecure)".to_owned()
+    } else if tls.fingerprint.is_some() {
+        "pinned fingerprint".to_owned()
+    } else if let Some(ref path) = tls.ca_bundle {
+        format!("CA bundle {}", path)
+    } else {
+        "system CA store".to_owned()
+    };
+    status.push_str(&format!("verification: {}\n", verification));
+
+    if let Some(ref path) = tls.client_cert {
+        status.push_str(&format!("client certificate: {}\n", path));
+    }
+
+    status
+}
+
+fn peer_fingerprint(ssl: &SslRef) -> Option<String> {
+    ssl.peer_certificate()
+        .and_then(|cert| cert.fingerprint(MessageDigest::sha256()).ok())
+        .map(|digest| {
+            digest.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+}
+
+// Fingerprints may be written with or without colons, in either case
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars()
+        .filter(|c| c.is_digit(16))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn tls_error<E: ::std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+// A connection to the IRC server that can be relayed to the irc crate
+trait Upstream: Read + Write {
+    fn fd(&self) -> RawFd;
+
+    // Data that has already been read from the socket, so poll can't see it
+    fn buffered(&self) -> usize {
+        0
+    }
+}
+
+impl Upstream for TcpStream {
+    fn fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+impl Upstream for SslStream<TcpStream> {
+    fn fd(&self) -> RawFd {
+        self.get_ref().as_raw_fd()
+    }
+
+    fn buffered(&self) -> usize {
+        self.ssl().pending()
+    }
+}
+
+// Anyone on this host can connect to the relay, so only the irc crate's
+// connection (one from this process) is accepted
+fn accept_and_relay<S: Upstream>(listener: TcpListener, upstream: S) {
+    for local in listener.incoming() {
+        let local = match local {
+            Ok(local) => local,
+            Err(_) => return,
+        };
+
+        let is_ours = match (local.peer_addr(), local.local_addr()) {
+            (Ok(peer), Ok(addr)) => is_own_socket(&peer, &addr),
+            _ => false,
+        };
+        if is_ours {
+            relay(upstream, local);
+            return;
+        }
+    }
+}
+
+// Whether the socket connected from `local` to `remote` belongs to this
+// process: it has to appear in /proc/net/tcp, with an inode that one of
+// our file descriptors refers to
+fn is_own_socket(local: &SocketAddr, remote: &SocketAddr) -> bool {
+    let inode = match socket_inode(local, remote) {
+        Some(inode) => inode,
+        None => return false,
+    };
+    let target = format!("socket:[{}]", inode);
+
+    match fs::read_dir("/proc/self/fd") {
+        Ok(fds) => fds.filter_map(|fd| fd.ok())
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|link| link.as_os_str() == target.as_str()),
+        Err(_) => false,
+    }
+}
+
+fn socket_inode(local: &SocketAddr, remote: &SocketAddr) -> Option<u64> {
+    let mut table = String::new();
+    if File::open("/proc/net/tcp").and_then(|mut file| file.read_to_string(&mut table)).is_err() {
+        return None;
+    }
+
+    let (local, remote) = match (proc_net_address(local), proc_net_address(remote)) {
+        (Some(local), Some(remote)) => (local, remote),
+        _ => return None,
+    };
+
+    // "sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode"
+    table.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 9 && fields[1] == local && fields[2] == remote)
+        .filter_map(|fields| fields[9].parse().ok())
+        .next()
+}
+
+// How /proc/net/tcp writes an address: 127.0.0.1:6667 is 0100007F:1A0B on
+// little-endian machines, since the address is printed as a native integer
+fn proc_net_address(addr: &SocketAddr) -> Option<String> {
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            Some(format!("{:08X}:{:04X}", u32::from_ne_bytes(addr.ip().octets()), addr.port()))
+        },
+        SocketAddr::V6(_) => None,
+    }
+}
+
+// Waits for either side to have data rather than polling them in turn, so
+// nothing is delayed and an idle connection doesn't wake us up
+fn relay<S: Upstream>(mut upstream: S, mut local: TcpStream) {
+    let mut buf = [0; 4096];
+
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: local.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: upstream.fd(), events: libc::POLLIN, revents: 0 },
+        ];
+
+        if upstream.buffered() > 0 {
+            fds[1].revents = libc::POLLIN;
+        } else if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            if Error::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+
+        if fds[0].revents != 0 {
+            match local.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    if upstream.write_all(&buf[..n]).and_then(|_| upstream.flush()).is_err() {
+                        return;
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => return,
+            }
+        }
+
+        if fds[1].revents != 0 {
+            match upstream.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    if local.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                },
+                Err(ref e) if is_timeout(e) => {},
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock
+        || e.kind() == ErrorKind::TimedOut
+        || e.kind() == ErrorKind::Interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::mem;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn proc_net_addresses() {
+        let address = |ip: &str, port: u16| proc_net_address(&SocketAddr::new(ip.parse().unwrap(), port));
+
+        if cfg!(target_endian = "little") {
+            assert_eq!(address("127.0.0.1", 6667).unwrap(), "0100007F:1A0B");
+            assert_eq!(address("10.1.2.254", 80).unwrap(), "FE02010A:0050");
+        } else {
+            assert_eq!(address("127.0.0.1", 6667).unwrap(), "7F000001:1A0B");
+            assert_eq!(address("10.1.2.254", 80).unwrap(), "0A0102FE:0050");
+        }
+        assert_eq!(address("0.0.0.0", 0).unwrap(), "00000000:0000");
+        assert_eq!(address("::1", 6667), None);
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(normalize_fingerprint("AB:cd:EF:01"), "abcdef01");
+        assert_eq!(normalize_fingerprint("abcdef01"), "abcdef01");
+        assert_eq!(normalize_fingerprint(" ab cd\n"), "abcd");
+        assert_eq!(normalize_fingerprint(""), "");
+    }
+
+    #[test]
+    fn own_sockets_are_recognized() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        assert!(is_own_socket(&accepted.peer_addr().unwrap(), &accepted.local_addr().unwrap()));
+        assert!(is_own_socket(&client.local_addr().unwrap(), &client.peer_addr().unwrap()));
+
+        // Nothing is connected this way round
+        let unused = "127.0.0.1:1".parse().unwrap();
+        assert!(! is_own_socket(&unused, &accepted.local_addr().unwrap()));
+    }
+
+    // Connects to port from a child process, sends line and waits to be
+    // killed. Only calls that are safe after a fork are made in the child.
+    fn connect_from_another_process(port: u16, line: &'static [u8]) -> libc::pid_t {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
+        let sockaddr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: port.to_be(),
+            sin_addr: libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() },
+            sin_zero: [0; 8],
+        };
+
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+                let connected = libc::connect(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                );
+                if connected == 0 {
+                    libc::write(fd, line.as_ptr() as *const libc::c_void, line.len());
+                }
+                loop {
+                    libc::pause();
+                }
+            }
+            assert!(pid > 0, "fork failed");
+            pid
+        }
+    }
+
+    #[test]
+    fn relay_refuses_other_processes() {
+        // Stands in for the IRC server
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let (server_side, _) = server.accept().unwrap();
+
+        let relay_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = relay_listener.local_addr().unwrap().port();
+        thread::spawn(move || accept_and_relay(relay_listener, upstream));
+
+        let child = connect_from_another_process(port, b"NICK intruder\r\n");
+        thread::sleep(Duration::from_millis(200));
+
+        let mut ours = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        ours.write_all(b"NICK ircfs\r\n").unwrap();
+
+        server_side.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut line = String::new();
+        BufReader::new(&server_side).read_line(&mut line).unwrap();
+
+        unsafe {
+            libc::kill(child, libc::SIGKILL);
+            libc::waitpid(child, ::std::ptr::null_mut(), 0);
+        }
+
+        assert_eq!(line, "NICK ircfs\r\n");
+    }
+}
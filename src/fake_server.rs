@@ -0,0 +1,97 @@
+// A stand-in IRC server on localhost for tests. It records every line the
+// client sends and lets the test send lines back. It only uses std, so the
+// mount tests under tests/ can include it as well.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to wait for the client before failing the test
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct FakeServer {
+    port: u16,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    received: Mutex<Receiver<String>>,
+}
+
+impl FakeServer {
+    pub fn start() -> FakeServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = Arc::new(Mutex::new(None));
+        let (tx, rx) = channel();
+
+        {
+            let client = client.clone();
+            thread::spawn(move || {
+                // One client at a time, like a real connection
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    *client.lock().unwrap() = stream.try_clone().ok();
+
+                    for line in BufReader::new(stream).lines() {
+                        match line {
+                            Ok(line) => if tx.send(line.trim_end_matches('\r').to_owned()).is_err() {
+                                return;
+                            },
+                            Err(_) => break,
+                        }
+                    }
+                }
+            });
+        }
+
+        FakeServer {
+            port: port,
+            client: client,
+            received: Mutex::new(rx),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Sends a line to the client, waiting for it to connect first
+    pub fn send(&self, line: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(ref mut stream) = *self.client.lock().unwrap() {
+                stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+                return;
+            }
+            assert!(Instant::now() < deadline, "nothing connected to the fake server");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Waits for the client to send a line starting with prefix and returns
+    // it. Lines before it are skipped.
+    pub fn expect(&self, prefix: &str) -> String {
+        let received = self.received.lock().unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match received.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => if line.starts_with(prefix) {
+                    return line;
+                },
+                Err(_) => panic!("timed out waiting for the client to send {:?}", prefix),
+            }
+        }
+    }
+
+    // Waits for the client to register, then welcomes it as nick
+    pub fn register(&self, nick: &str) {
+        self.expect("USER ");
+        self.send(&format!(":irc.test 001 {} :Welcome to the test network", nick));
+        self.send(&format!(":irc.test 005 {} CHANTYPES=# CASEMAPPING=ascii :are supported by this server", nick));
+    }
+}
@@ -4,23 +4,35 @@ extern crate time;
 
 use std::collections::HashMap;
 use std::ffi::{OsString, OsStr};
-use std::path::{Path, PathBuf};
-use std::io::{self, Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read};
+use std::sync::{Arc, Mutex, RwLock};
+use time::Timespec;
 
+use attributes::*;
 use permissions::*;
 
 pub struct Filesystem {
     fake_root: FuseDir,
+    file_mode: u16,
+    dir_mode: u16,
+    attributes: AttributeStore,
 }
 
 impl Filesystem {
-    pub fn new(uid: u32, gid: u32) -> Self {
-        let mut root = FuseDir::new(uid, gid);
-        root.mk_dir("/", uid, gid).unwrap();
+    pub fn new(uid: u32, gid: u32, file_mode: u16, dir_mode: u16, attributes: AttributeStore) -> Self {
+        let mut root = FuseDir::new(uid, gid, dir_mode);
+        root.mk_dir("/", uid, gid, dir_mode).unwrap();
 
-        Filesystem {
+        let mut fs = Filesystem {
             fake_root: root,
-        }
+            file_mode: file_mode,
+            dir_mode: dir_mode,
+            attributes: attributes,
+        };
+        fs.apply_saved_attributes("/");
+        fs
     }
 
     pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&Node> {
@@ -34,33 +46,31 @@ impl Filesystem {
     pub fn mk_dir<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
         let uid = self.root_dir().attr.uid;
         let gid = self.root_dir().attr.gid;
-        self.fake_root.mk_dir(path, uid, gid)
+        let mode = self.dir_mode;
+        self.fake_root.mk_dir(&path, uid, gid, mode)?;
+        self.apply_saved_attributes(path);
+        Ok(())
     }
 
+    // Creates the directory at path and any missing directories above it
     pub fn mk_parents<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
         let full_path = path.as_ref();
-        let mut partial_path = PathBuf::new();
 
-        if ! full_path.is_absolute() {
+        if ! full_path.is_absolute() || ! is_normal(full_path) {
             return Err(Error::from(ErrorKind::InvalidInput));
         }
 
-        let mut iter = full_path.iter();
-
-        while let None = self.fake_root.get(&full_path) {
-            let next = iter.next().unwrap();
-            partial_path = partial_path.join(&next);
-
-            if let Err(e) = self.mk_dir(&partial_path) {
-                match e.kind() {
-                    ErrorKind::Other => {
-                        return Err(e);
-                    },
-                    ErrorKind::InvalidInput => {
-                        return Err(e);
-                    },
-                    _ => {},
-                }
+        let mut partial_path = PathBuf::new();
+        for segment in full_path.iter() {
+            partial_path.push(segment);
+            match self.get(&partial_path) {
+                Some(&Node::D(ref _dir)) => {},
+                Some(&Node::F(ref _file)) => {
+                    return Err(Error::new(ErrorKind::Other, "Not a directory"));
+                },
+                None => {
+                    self.mk_dir(&partial_path)?;
+                },
             }
         }
 
@@ -70,13 +80,84 @@ impl Filesystem {
     pub fn mk_ro_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
         let uid = self.root_dir().attr.uid;
         let gid = self.root_dir().attr.gid;
-        self.fake_root.mk_ro_file(path, uid, gid)
+        let mode = self.file_mode;
+        self.fake_root.mk_ro_file(&path, uid, gid, mode)?;
+        self.apply_saved_attributes(path);
+        Ok(())
     }
 
     pub fn mk_rw_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
         let uid = self.root_dir().attr.uid;
         let gid = self.root_dir().attr.gid;
-        self.fake_root.mk_rw_file(path, uid, gid)
+        let mode = self.file_mode;
+        self.fake_root.mk_rw_file(&path, uid, gid, mode)?;
+        self.apply_saved_attributes(path);
+        Ok(())
+    }
+
+    // Removes a file, or a directory along with everything in it
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.fake_root.remove_node(path).map(|_| ())
+    }
+
+    // Changes to modes and owners are saved, but not written out, which is left
+    // to the caller so that it can be done without the filesystem locked
+    pub fn set_mode<P: AsRef<Path>>(&mut self, path: P, mode: Mode) -> io::Result<AttributeSnapshot> {
+        match self.get_mut(&path) {
+            Some(node) => {
+                node.update_attr(|attr| {
+                    attr.perm = mode.as_int();
+                    attr.ctime = time::get_time();
+                });
+            },
+            None => return Err(Error::from(ErrorKind::NotFound)),
+        }
+
+        Ok(self.attributes.update(path, |saved| saved.mode = Some(mode.as_int())))
+    }
+
+    pub fn set_owner<P: AsRef<Path>>(&mut self, path: P, uid: Option<u32>, gid: Option<u32>) -> io::Result<AttributeSnapshot> {
+        match self.get_mut(&path) {
+            Some(node) => {
+                node.update_attr(|attr| {
+                    if let Some(uid) = uid {
+                        attr.uid = uid;
+                    }
+                    if let Some(gid) = gid {
+                        attr.gid = gid;
+                    }
+                    attr.ctime = time::get_time();
+                });
+            },
+            None => return Err(Error::from(ErrorKind::NotFound)),
+        }
+
+        Ok(self.attributes.update(path, |saved| {
+            if uid.is_some() {
+                saved.uid = uid;
+            }
+            if gid.is_some() {
+                saved.gid = gid;
+            }
+        }))
+    }
+
+    fn apply_saved_attributes<P: AsRef<Path>>(&mut self, path: P) {
+        if let Some(saved) = self.attributes.get(&path) {
+            if let Some(node) = self.get_mut(&path) {
+                node.update_attr(|attr| {
+                    if let Some(mode) = saved.mode {
+                        attr.perm = mode;
+                    }
+                    if let Some(uid) = saved.uid {
+                        attr.uid = uid;
+                    }
+                    if let Some(gid) = saved.gid {
+                        attr.gid = gid;
+                    }
+                });
+            }
+        }
     }
 
     pub fn dir_entries<P: AsRef<Path>>(&self, path: P)
@@ -98,9 +179,26 @@ impl Filesystem {
     }
 
     // Checks traversal (+x) of each segment of the path
-    #[allow(unused_variables)]
     pub fn can_access<P: AsRef<Path>>(&self, path: P, req: &RequestInfo) -> bool {
-        unimplemented!();
+        let parent = match path.as_ref().parent() {
+            Some(parent) => parent,
+            None => return true,
+        };
+
+        let mut partial_path = PathBuf::new();
+        for segment in parent.iter() {
+            partial_path.push(segment);
+            match self.get(&partial_path) {
+                Some(&Node::D(ref dir)) => {
+                    if ! can_execute(dir.attr.uid, dir.attr.gid, dir.attr.perm, req) {
+                        return false;
+                    }
+                },
+                _ => return false,
+            }
+        }
+
+        true
     }
 
     fn root_dir(&self) -> &FuseDir {
@@ -115,24 +213,24 @@ impl Filesystem {
 
 pub struct FuseDir {
     tree: HashMap<OsString, Node>,
-    attr: FileAttr,
+    pub attr: FileAttr,
 }
 
 impl FuseDir {
-    fn new(uid: u32, gid: u32) -> Self {
+    fn new(uid: u32, gid: u32, mode: u16) -> Self {
         let init_time = time::get_time();
 
         FuseDir {
             tree: HashMap::new(),
             attr: FileAttr {
-                size: 4096,
-                blocks: 8,
+                size: 0,
+                blocks: 0,
                 atime: init_time,
                 mtime: init_time,
                 ctime: init_time,
                 crtime: init_time,
                 kind: FileType::Directory,
-                perm: 0o700,
+                perm: mode,
                 nlink: 2,
                 uid: uid,
                 gid: gid,
@@ -196,21 +294,57 @@ impl FuseDir {
         Some(node)
     }
 
-    fn mk_dir<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> io::Result<()> {
-        self.insert_node(path, FuseDir::new(uid, gid).into())
+    fn mk_dir<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32, mode: u16) -> io::Result<()> {
+        self.insert_node(path, FuseDir::new(uid, gid, mode).into())
+    }
+
+    fn mk_rw_file<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32, mode: u16) -> io::Result<()> {
+        self.insert_node(path, FuseFile::new_rw(uid, gid, mode).into())
     }
 
-    fn mk_rw_file<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> io::Result<()> {
-        self.insert_node(path, FuseFile::new_rw(uid, gid).into())
+    fn mk_ro_file<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32, mode: u16) -> io::Result<()> {
+        self.insert_node(path, FuseFile::new_ro(uid, gid, mode).into())
     }
 
-    fn mk_ro_file<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> io::Result<()> {
-        self.insert_node(path, FuseFile::new_ro(uid, gid).into())
+    // Adds an entry, keeping the directory's link count, size and times up to date.
+    // Each subdirectory's ".." is a link to this directory.
+    fn add_child(&mut self, name: &OsStr, node: Node) {
+        if let Node::D(_) = node {
+            self.attr.nlink += 1;
+        }
+        self.tree.insert(name.to_owned(), node);
+        self.touch_entries();
+    }
+
+    fn remove_child(&mut self, name: &OsStr) -> Option<Node> {
+        let node = match self.tree.remove(name) {
+            Some(node) => node,
+            None => return None,
+        };
+
+        if let Node::D(_) = node {
+            self.attr.nlink -= 1;
+        }
+        self.touch_entries();
+
+        Some(node)
+    }
+
+    // Updates size and times after an entry has been added or removed
+    fn touch_entries(&mut self) {
+        let current_time = time::get_time();
+        self.attr.size = self.tree.len() as u64;
+        self.attr.mtime = current_time;
+        self.attr.ctime = current_time;
     }
 
     fn insert_node<P: AsRef<Path>>(&mut self, path: P, node: Node) -> io::Result<()> {
         let path = path.as_ref();
 
+        if ! is_normal(path) {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
         // Needed for making the root
         if path == Path::new("/") {
             if let Some(_node) = self.tree.get(OsStr::new(path)) {
@@ -219,59 +353,91 @@ impl FuseDir {
             self.tree.insert(OsString::from("/"), node);
             Ok(())
         } else {
-            let parent = path.parent();
             let filename = path.file_name()
                 .ok_or(Error::from(ErrorKind::InvalidInput,))?;
 
-            if parent == Some(&Path::new("")) {
-                if let Some(_n) = self.tree.get_mut(filename) {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "File already exists"));
-                }
-                self.tree.insert(filename.to_owned(), node);
-                Ok(())
-            } else {
-                if let Some(segment) = parent {
-                    match self.get_mut(segment) {
-                        Some(&mut Node::D(ref mut dir)) => {
-                            if let Some(_e) = dir.get(Path::new(filename)) {
-                                return Err(Error::from(ErrorKind::AlreadyExists));
-                            }
-                            dir.tree.insert(filename.to_owned(), node);
-                            Ok(())
-                        },
-                        Some(&mut Node::F(ref mut _file)) => {
-                            Err(Error::from(ErrorKind::Other))
-                        },
-                        None => {
-                            Err(Error::from(ErrorKind::NotFound))
-                        },
+            // Everything else goes under the root. A relative path would put
+            // the node next to it, where nothing can reach it.
+            if ! path.is_absolute() {
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+
+            match path.parent().and_then(|parent| self.get_mut(parent)) {
+                Some(&mut Node::D(ref mut dir)) => {
+                    if dir.tree.contains_key(filename) {
+                        return Err(Error::from(ErrorKind::AlreadyExists));
                     }
-                } else {
+                    dir.add_child(filename, node);
+                    Ok(())
+                },
+                Some(&mut Node::F(ref mut _file)) => {
+                    Err(Error::from(ErrorKind::Other))
+                },
+                None => {
                     Err(Error::from(ErrorKind::NotFound))
-                }
+                },
             }
         }
     }
+
+    // The root can't be removed
+    fn remove_node<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Node> {
+        let path = path.as_ref();
+
+        // As in insert_node, a relative path can't name anything in the tree
+        if ! path.is_absolute() || ! is_normal(path) {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+
+        let (parent, filename) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(filename)) => (parent, filename),
+            _ => return Err(Error::from(ErrorKind::InvalidInput)),
+        };
+
+        match self.get_mut(parent) {
+            Some(&mut Node::D(ref mut dir)) => {
+                dir.remove_child(filename).ok_or(Error::from(ErrorKind::NotFound))
+            },
+            Some(&mut Node::F(ref mut _file)) => {
+                Err(Error::from(ErrorKind::Other))
+            },
+            None => {
+                Err(Error::from(ErrorKind::NotFound))
+            },
+        }
+    }
 }
 
+// The contents and attributes of a file have a lock of their own, so that
+// reading or appending to one file only needs the tree to be locked for
+// reading, and doesn't hold up operations on other files. Cloning a FuseFile
+// gives another handle to the same file.
+#[derive(Clone)]
 pub struct FuseFile {
-    pub attr: FileAttr,
+    inner: Arc<RwLock<FileInner>>,
+    // Kept apart from the rest of the attributes so that reads, which only
+    // update this, can share the lock on the data
+    atime: Arc<Mutex<Timespec>>,
+}
+
+struct FileInner {
+    attr: FileAttr,
     data: Vec<u8>,
 }
 
 impl FuseFile {
-    pub fn new_rw(uid: u32, gid: u32) -> Self {
+    pub fn new_rw(uid: u32, gid: u32, mode: u16) -> Self {
         let init_time = time::get_time();
 
         let attr = FileAttr {
             size: 0,
-            blocks: 1,
+            blocks: 0,
             atime: init_time,
             mtime: init_time,
             ctime: init_time,
             crtime: init_time,
             kind: FileType::RegularFile,
-            perm: 0o600,
+            perm: mode,
             nlink: 1,
             uid: uid,
             gid: gid,
@@ -280,48 +446,77 @@ impl FuseFile {
         };
 
         FuseFile {
-            attr: attr,
-            data: Vec::new(),
+            inner: Arc::new(RwLock::new(FileInner {
+                attr: attr,
+                data: Vec::new(),
+            })),
+            atime: Arc::new(Mutex::new(init_time)),
         }
     }
 
-    pub fn new_ro(uid: u32, gid: u32) -> Self {
-        let init_time = time::get_time();
+    // Same as new_rw, but with the write bits of mode masked off
+    pub fn new_ro(uid: u32, gid: u32, mode: u16) -> Self {
+        FuseFile::new_rw(uid, gid, mode & !0o222)
+    }
 
-        let attr = FileAttr {
-            size: 0,
-            blocks: 1,
-            atime: init_time,
-            mtime: init_time,
-            ctime: init_time,
-            crtime: init_time,
-            kind: FileType::RegularFile,
-            perm: 0o400,
-            nlink: 1,
-            uid: uid,
-            gid: gid,
-            rdev: 0,
-            flags: 0,
-        };
+    pub fn attr(&self) -> FileAttr {
+        let mut attr = self.inner.read().unwrap().attr;
+        attr.atime = *self.atime.lock().unwrap();
+        attr
+    }
 
-        FuseFile {
-            attr: attr,
-            data: Vec::new(),
-        }
+    pub fn contents(&self) -> Vec<u8> {
+        self.inner.read().unwrap().data.clone()
     }
 
-    pub fn data(&self) -> &[u8] {
-        &self.data
+    pub fn update_attr<F: FnOnce(&mut FileAttr)>(&self, f: F) {
+        let mut inner = self.inner.write().unwrap();
+        let mut atime = self.atime.lock().unwrap();
+
+        inner.attr.atime = *atime;
+        f(&mut inner.attr);
+        *atime = inner.attr.atime;
+    }
+
+    // Returns up to size bytes starting at offset
+    pub fn read_at(&self, offset: u64, size: u32) -> Vec<u8> {
+        let inner = self.inner.read().unwrap();
+        *self.atime.lock().unwrap() = time::get_time();
+
+        read_slice(&inner.data, offset, size).to_owned()
+    }
+
+    pub fn insert_data(&self, data: &[u8]) {
+        let mut inner = self.inner.write().unwrap();
+        inner.data.extend_from_slice(data);
+        inner.touch_data();
     }
 
-    pub fn insert_data(&mut self, data: &[u8]) {
-        self.data.extend_from_slice(data);
+    pub fn replace_data(&self, data: &[u8]) {
+        let mut inner = self.inner.write().unwrap();
+        inner.data.clear();
+        inner.data.extend_from_slice(data);
+        inner.touch_data();
+    }
+
+    // Files can only be shrunk; there's no sensible way to extend a log
+    pub fn truncate(&self, size: u64) {
+        let mut inner = self.inner.write().unwrap();
+        if size < inner.data.len() as u64 {
+            inner.data.truncate(size as usize);
+            inner.touch_data();
+        }
+    }
+}
+
+impl FileInner {
+    // Updates size and times after the data has changed
+    fn touch_data(&mut self) {
         let current_time = time::get_time();
         self.attr.size = self.data.len() as u64;
-        self.attr.atime = current_time;
+        self.attr.blocks = (self.attr.size + 511) / 512;
         self.attr.mtime = current_time;
         self.attr.ctime = current_time;
-        self.attr.crtime = current_time;
     }
 }
 
@@ -332,17 +527,17 @@ pub enum Node {
 
 
 impl Node {
-    pub fn attr(&self) -> &FileAttr {
+    pub fn attr(&self) -> FileAttr {
         match *self {
-            Node::D(ref dir) => &dir.attr,
-            Node::F(ref file) => &file.attr,
+            Node::D(ref dir) => dir.attr,
+            Node::F(ref file) => file.attr(),
         }
     }
 
-    pub fn attr_mut(&mut self) -> &mut FileAttr {
+    pub fn update_attr<F: FnOnce(&mut FileAttr)>(&mut self, f: F) {
         match *self {
-            Node::D(ref mut dir) => &mut dir.attr,
-            Node::F(ref mut file) => &mut file.attr,
+            Node::D(ref mut dir) => f(&mut dir.attr),
+            Node::F(ref file) => file.update_attr(f),
         }
     }
 
@@ -374,12 +569,51 @@ impl From<FuseDir> for Node {
     }
 }
 
+// Paths in the tree can't contain "." or ".." (or Windows prefixes), since
+// they're looked up one name at a time
+fn is_normal(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::RootDir | Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+// The part of data that a read of size bytes at offset returns: empty when
+// offset is at or past the end, and cut short at the end of the data
+pub fn read_slice(data: &[u8], offset: u64, size: u32) -> &[u8] {
+    let len = data.len() as u64;
+    let start = offset.min(len);
+    let end = offset.saturating_add(size as u64).min(len);
+
+    &data[start as usize..end as usize]
+}
+
+// Whether the process making a request is in a group, either as its primary
+// group or as one of the supplementary groups listed in /proc/PID/status
+pub fn in_group(gid: u32, req: &RequestInfo) -> bool {
+    if gid == req.gid {
+        return true;
+    }
+
+    let mut status = String::new();
+    if File::open(format!("/proc/{}/status", req.pid))
+        .and_then(|mut file| file.read_to_string(&mut status))
+        .is_err() {
+        return false;
+    }
+
+    status.lines()
+        .find(|line| line.starts_with("Groups:"))
+        .map(|line| line["Groups:".len()..].split_whitespace().any(|g| g.parse() == Ok(gid)))
+        .unwrap_or(false)
+}
+
 pub fn can_read(uid: u32, gid: u32, mode: u16, req: &RequestInfo) -> bool {
     let mode = Mode::new(mode).unwrap();
 
     if uid == req.uid {
         mode.user.read
-    } else if gid == req.gid {
+    } else if in_group(gid, req) {
         mode.group.read
     } else {
         mode.other.read
@@ -391,7 +625,7 @@ pub fn can_write(uid: u32, gid: u32, mode: u16, req: &RequestInfo) -> bool {
 
     if uid == req.uid {
         mode.user.write
-    } else if gid == req.gid {
+    } else if in_group(gid, req) {
         mode.group.write
     } else {
         mode.other.write
@@ -403,9 +637,322 @@ pub fn can_execute(uid: u32, gid: u32, mode: u16, req: &RequestInfo) -> bool {
 
     if uid == req.uid {
         mode.user.execute
-    } else if gid == req.gid {
+    } else if in_group(gid, req) {
         mode.group.execute
     } else {
         mode.other.execute
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a read should return, worked out the slow way
+    fn expected_slice(data: &[u8], offset: u64, size: u32) -> Vec<u8> {
+        data.iter()
+            .enumerate()
+            .filter(|&(i, _)| (i as u64) >= offset && ((i as u64) - offset) < size as u64)
+            .map(|(_, &byte)| byte)
+            .collect()
+    }
+
+    // A small xorshift generator, so that the "random" cases are the same
+    // on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn read_slice_matches_model_for_small_offsets_and_sizes() {
+        let data = (0..64).collect::<Vec<u8>>();
+
+        for len in 0..data.len() + 1 {
+            for offset in 0..80 {
+                for size in 0..80 {
+                    assert_eq!(
+                        read_slice(&data[..len], offset, size),
+                        &expected_slice(&data[..len], offset, size)[..],
+                        "len {} offset {} size {}", len, offset, size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_slice_matches_model_for_arbitrary_offsets_and_sizes() {
+        let data = (0..10000).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+
+        for _ in 0..10000 {
+            // Mostly within or just past the data, sometimes anywhere at all
+            let offset = match rng.next() % 4 {
+                0 => rng.next(),
+                _ => rng.next() % 12000,
+            };
+            let size = match rng.next() % 4 {
+                0 => rng.next() as u32,
+                _ => (rng.next() % 6000) as u32,
+            };
+
+            let slice = read_slice(&data, offset, size);
+            assert!(slice.len() <= size as usize);
+            if offset < data.len() as u64 {
+                let start = offset as usize;
+                let end = (start + slice.len()).min(data.len());
+                assert_eq!(slice, &data[start..end], "offset {} size {}", offset, size);
+                assert_eq!(slice.len() as u64, (data.len() as u64 - offset).min(size as u64));
+            } else {
+                assert!(slice.is_empty(), "offset {} size {}", offset, size);
+            }
+        }
+    }
+
+    #[test]
+    fn read_slice_past_the_end_is_empty() {
+        let data = b"hello";
+
+        assert!(read_slice(data, 5, 10).is_empty());
+        assert!(read_slice(data, 6, 10).is_empty());
+        assert!(read_slice(data, u64::max_value(), 10).is_empty());
+        assert!(read_slice(&[], 0, 4096).is_empty());
+    }
+
+    #[test]
+    fn read_slice_overflowing_offset_and_size() {
+        let data = b"hello";
+
+        assert!(read_slice(data, u64::max_value(), u32::max_value()).is_empty());
+        assert!(read_slice(data, u64::max_value() - 1, 2).is_empty());
+        assert_eq!(read_slice(data, 1, u32::max_value()), b"ello");
+    }
+
+    #[test]
+    fn reads_in_chunks_reassemble_the_file() {
+        let file = FuseFile::new_ro(1000, 1000, 0o600);
+        let contents = (0..10000).map(|i| format!("line {}\n", i)).collect::<String>();
+        file.insert_data(contents.as_bytes());
+
+        for &chunk in &[1, 7, 4096, 65536] {
+            let mut read = Vec::new();
+            loop {
+                let data = file.read_at(read.len() as u64, chunk);
+                if data.is_empty() {
+                    break;
+                }
+                assert!(data.len() <= chunk as usize);
+                read.extend_from_slice(&data);
+            }
+            assert_eq!(read, contents.as_bytes(), "chunk size {}", chunk);
+        }
+    }
+
+    #[test]
+    fn reads_share_the_lock_and_update_atime() {
+        let file = FuseFile::new_ro(1000, 1000, 0o600);
+        file.insert_data(b"hello\n");
+        file.update_attr(|attr| attr.atime = Timespec::new(1, 0));
+        assert_eq!(file.attr().atime, Timespec::new(1, 0));
+
+        // Would deadlock if reading needed the lock to itself
+        let other_reader = file.inner.read().unwrap();
+        assert_eq!(file.read_at(0, 4096), b"hello\n");
+        drop(other_reader);
+
+        let attr = file.attr();
+        assert!(attr.atime > Timespec::new(1, 0));
+        assert_eq!(attr.size, 6);
+
+        // Other attribute changes leave it alone
+        file.update_attr(|attr| attr.perm = 0o640);
+        assert_eq!(file.attr().atime, attr.atime);
+        assert_eq!(file.clone().attr().atime, attr.atime);
+    }
+
+    #[test]
+    fn read_spanning_appends() {
+        let file = FuseFile::new_ro(1000, 1000, 0o600);
+        file.insert_data(b"first\n");
+        file.insert_data(b"second\n");
+
+        assert_eq!(file.read_at(3, 6), b"st\nsec");
+        assert_eq!(file.read_at(6, 4096), b"second\n");
+        assert_eq!(file.attr().size, 13);
+    }
+
+    fn filesystem() -> Filesystem {
+        Filesystem::new(1000, 1000, 0o600, 0o700, AttributeStore::new())
+    }
+
+    fn attr(fs: &Filesystem, path: &str) -> FileAttr {
+        fs.get(path).unwrap().attr()
+    }
+
+    fn names(fs: &Filesystem, path: &str) -> Vec<String> {
+        let mut names = fs.dir_entries(path).unwrap().into_iter()
+            .map(|entry| entry.name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn root_is_under_its_own_key() {
+        let mut fs = filesystem();
+
+        assert_eq!(attr(&fs, "/").kind, FileType::Directory);
+        assert_eq!(attr(&fs, "/").perm, 0o700);
+        assert!(names(&fs, "/").is_empty());
+        assert!(fs.get("").is_none());
+        assert_eq!(fs.mk_dir("/").unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn mk_parents_creates_what_is_missing() {
+        let mut fs = filesystem();
+
+        fs.mk_parents("/a/b/c").unwrap();
+        fs.mk_parents("/a/b/c").unwrap();
+        fs.mk_parents("/a/b/d/").unwrap();
+        fs.mk_parents("/").unwrap();
+
+        for path in &["/a", "/a/b", "/a/b/c", "/a/b/d"] {
+            assert_eq!(attr(&fs, path).kind, FileType::Directory, "{}", path);
+        }
+        assert_eq!(names(&fs, "/a/b"), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn mk_parents_rejects_odd_paths() {
+        let mut fs = filesystem();
+
+        for path in &["", "a", "a/b", "./a", "/a/..", "/a/../b", "/.."] {
+            assert_eq!(fs.mk_parents(path).unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", path);
+        }
+        assert!(names(&fs, "/").is_empty());
+
+        // Path leaves out a "." in the middle, so this is just /a/b
+        fs.mk_parents("/a/./b").unwrap();
+        assert!(fs.get("/a/b").is_some());
+    }
+
+    #[test]
+    fn mk_parents_stops_at_a_file() {
+        let mut fs = filesystem();
+        fs.mk_ro_file("/file").unwrap();
+
+        assert_eq!(fs.mk_parents("/file/dir").unwrap_err().kind(), ErrorKind::Other);
+        assert_eq!(attr(&fs, "/file").kind, FileType::RegularFile);
+        assert!(fs.get("/file/dir").is_none());
+    }
+
+    #[test]
+    fn insert_node_needs_an_absolute_path() {
+        let mut root = FuseDir::new(1000, 1000, 0o700);
+        root.mk_dir("/", 1000, 1000, 0o700).unwrap();
+
+        // No parent but the empty path, which would put it next to the root
+        assert_eq!(root.mk_dir("dir", 1000, 1000, 0o700).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(root.mk_rw_file("file", 1000, 1000, 0o600).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(root.get("dir").is_none());
+        assert!(root.get("file").is_none());
+        assert_eq!(root.tree.len(), 1);
+    }
+
+    #[test]
+    fn insert_node_needs_a_parent_directory() {
+        let mut fs = filesystem();
+        fs.mk_ro_file("/file").unwrap();
+
+        assert_eq!(fs.mk_dir("/missing/dir").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.mk_ro_file("/file/file").unwrap_err().kind(), ErrorKind::Other);
+        assert_eq!(fs.mk_rw_file("/file").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(names(&fs, "/"), vec!["file"]);
+    }
+
+    #[test]
+    fn remove_takes_everything_below() {
+        let mut fs = filesystem();
+        fs.mk_parents("/a/b").unwrap();
+        fs.mk_ro_file("/a/b/receive").unwrap();
+        fs.mk_rw_file("/a/send").unwrap();
+        fs.mk_dir("/c").unwrap();
+
+        fs.remove("/a").unwrap();
+        assert!(fs.get("/a").is_none());
+        assert!(fs.get("/a/b/receive").is_none());
+        assert_eq!(names(&fs, "/"), vec!["c"]);
+
+        assert_eq!(fs.remove("/a").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.remove("/a/b").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn remove_rejects_odd_paths() {
+        let mut fs = filesystem();
+        fs.mk_dir("/a").unwrap();
+        fs.mk_ro_file("/file").unwrap();
+
+        assert_eq!(fs.remove("/").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.remove("").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.remove("/a/..").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.remove("/file/x").unwrap_err().kind(), ErrorKind::Other);
+        assert_eq!(fs.remove("a").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.remove("a/b").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.remove("/missing").unwrap_err().kind(), ErrorKind::NotFound);
+
+        assert!(fs.get("/").is_some());
+        assert_eq!(names(&fs, "/"), vec!["a", "file"]);
+    }
+
+    #[test]
+    fn link_counts_and_sizes_follow_the_entries() {
+        let mut fs = filesystem();
+        assert_eq!((attr(&fs, "/").nlink, attr(&fs, "/").size), (2, 0));
+
+        // Each subdirectory links back to its parent with ".."; files don't
+        fs.mk_dir("/a").unwrap();
+        fs.mk_ro_file("/file").unwrap();
+        assert_eq!((attr(&fs, "/").nlink, attr(&fs, "/").size), (3, 2));
+        assert_eq!(attr(&fs, "/file").nlink, 1);
+
+        fs.mk_parents("/a/b/c").unwrap();
+        fs.mk_rw_file("/a/send").unwrap();
+        assert_eq!((attr(&fs, "/a").nlink, attr(&fs, "/a").size), (3, 2));
+        assert_eq!((attr(&fs, "/a/b").nlink, attr(&fs, "/a/b").size), (3, 1));
+        assert_eq!((attr(&fs, "/a/b/c").nlink, attr(&fs, "/a/b/c").size), (2, 0));
+        assert_eq!(attr(&fs, "/").nlink, 3);
+
+        fs.remove("/a/b").unwrap();
+        assert_eq!((attr(&fs, "/a").nlink, attr(&fs, "/a").size), (2, 1));
+
+        fs.remove("/a").unwrap();
+        fs.remove("/file").unwrap();
+        assert_eq!((attr(&fs, "/").nlink, attr(&fs, "/").size), (2, 0));
+    }
+
+    #[test]
+    fn names_with_odd_characters() {
+        let mut fs = filesystem();
+        let names_in = ["#chan nel", "#ünïcödé", "&local", "#a%2Fb", "#\\", "-"];
+
+        for name in &names_in {
+            let path = format!("/{}", name);
+            fs.mk_parents(&path).unwrap();
+            fs.mk_ro_file(format!("{}/receive", path)).unwrap();
+            assert_eq!(names(&fs, &path), vec!["receive"], "{}", name);
+        }
+
+        let mut expected = names_in.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(names(&fs, "/"), expected);
+    }
+}
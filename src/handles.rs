@@ -0,0 +1,171 @@
+use libc::{c_int, EFBIG, EINVAL};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Largest a mode list file can be made by writing to it. Servers keep lists
+// to a few hundred masks at most, so this is plenty.
+pub const LIST_LIMIT: u64 = 64 * 1024;
+
+// State kept for each open file or directory
+pub enum Handle {
+    // Data written that hasn't been terminated by a newline yet
+    Send(Vec<u8>),
+    // Offset that this handle has read up to
+    Receive(u64),
+    // New contents for a mode list file (e.g. "bans"), which are compared
+    // with the current list when the file is closed
+    List(Vec<u8>),
+    Dir,
+}
+
+impl Handle {
+    // Buffers data written to a send handle, returning everything up to and
+    // including the last newline. The remainder stays in the buffer.
+    pub fn push_input(&mut self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Handle::Send(ref mut buffer) => {
+                buffer.extend_from_slice(data);
+                match buffer.iter().rposition(|&b| b == b'\n') {
+                    Some(end) => {
+                        let rest = buffer.split_off(end + 1);
+                        ::std::mem::replace(buffer, rest)
+                    },
+                    None => Vec::new(),
+                }
+            },
+            _ => data.to_owned(),
+        }
+    }
+
+    // Writes into a list handle's contents, extending them as needed. Writes
+    // that would leave a gap or make the list bigger than LIST_LIMIT are
+    // refused, leaving the contents as they were.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        if let Handle::List(ref mut contents) = *self {
+            if offset > contents.len() as u64 {
+                return Err(EINVAL);
+            }
+            let end = match offset.checked_add(data.len() as u64) {
+                Some(end) if end <= LIST_LIMIT => end as usize,
+                _ => return Err(EFBIG),
+            };
+
+            let offset = offset as usize;
+            if contents.len() < end {
+                contents.resize(end, 0);
+            }
+            contents[offset..end].copy_from_slice(data);
+        }
+
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, size: u64) {
+        if let Handle::List(ref mut contents) = *self {
+            contents.truncate(size as usize);
+        }
+    }
+
+    // Takes whatever is left in a send handle's buffer
+    pub fn take_input(&mut self) -> Vec<u8> {
+        match *self {
+            Handle::Send(ref mut buffer) => ::std::mem::replace(buffer, Vec::new()),
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub struct Handles {
+    table: Mutex<HandleTable>,
+}
+
+struct HandleTable {
+    next: u64,
+    handles: HashMap<u64, Handle>,
+}
+
+impl Handles {
+    pub fn new() -> Self {
+        Handles {
+            table: Mutex::new(HandleTable {
+                next: 1,
+                handles: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn insert(&self, handle: Handle) -> u64 {
+        let mut table = self.table.lock().unwrap();
+        let fh = table.next;
+        table.next += 1;
+        table.handles.insert(fh, handle);
+        fh
+    }
+
+    pub fn remove(&self, fh: u64) -> Option<Handle> {
+        self.table.lock().unwrap().handles.remove(&fh)
+    }
+
+    pub fn with<F, T>(&self, fh: u64, f: F) -> Option<T>
+    where F: FnOnce(&mut Handle) -> T {
+        self.table.lock().unwrap().handles.get_mut(&fh).map(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(contents: &[u8]) -> Handle {
+        Handle::List(contents.to_owned())
+    }
+
+    fn contents(handle: &Handle) -> &[u8] {
+        match *handle {
+            Handle::List(ref contents) => contents,
+            _ => panic!("not a list handle"),
+        }
+    }
+
+    #[test]
+    fn write_at_appends_and_overwrites() {
+        let mut handle = list(b"*!*@a\n");
+        handle.write_at(6, b"*!*@b\n").unwrap();
+        assert_eq!(contents(&handle), b"*!*@a\n*!*@b\n");
+
+        handle.write_at(4, b"c").unwrap();
+        assert_eq!(contents(&handle), b"*!*@c\n*!*@b\n");
+
+        handle.write_at(0, b"").unwrap();
+        assert_eq!(contents(&handle), b"*!*@c\n*!*@b\n");
+    }
+
+    #[test]
+    fn write_at_refuses_gaps() {
+        let mut handle = list(b"*!*@a\n");
+        assert_eq!(handle.write_at(7, b"*!*@b\n"), Err(EINVAL));
+        assert_eq!(handle.write_at(u64::max_value(), b"x"), Err(EINVAL));
+        assert_eq!(contents(&handle), b"*!*@a\n");
+    }
+
+    #[test]
+    fn write_at_refuses_lists_that_are_too_big() {
+        let mut handle = list(b"");
+        let line = vec![b'x'; 1024];
+        for i in 0..LIST_LIMIT / 1024 {
+            handle.write_at(i * 1024, &line).unwrap();
+        }
+        assert_eq!(contents(&handle).len() as u64, LIST_LIMIT);
+
+        assert_eq!(handle.write_at(LIST_LIMIT, b"x"), Err(EFBIG));
+        assert_eq!(handle.write_at(LIST_LIMIT - 1, b"xx"), Err(EFBIG));
+        assert_eq!(contents(&handle).len() as u64, LIST_LIMIT);
+    }
+
+    #[test]
+    fn write_at_only_touches_list_handles() {
+        let mut handle = Handle::Receive(0);
+        assert_eq!(handle.write_at(u64::max_value(), b"x"), Ok(()));
+    }
+}
@@ -1,169 +1,407 @@
-use libc::{ENOENT, EISDIR, ENOTSUP};
+use libc::{ENOENT, ENOTDIR, EACCES, EEXIST, EINVAL, EIO, EISDIR, EPERM, O_ACCMODE, O_RDONLY, O_TRUNC, O_WRONLY, R_OK, W_OK, X_OK};
 use time::{self, Timespec};
 
 use irc::client::prelude::*;
 use irc::error::Result as IrcResult;
+use irc::proto::command::{BatchSubCommand, CapSubCommand};
+use irc::proto::mode::{ChannelMode, Mode as IrcMode};
+use irc::proto::message::Tag;
 
+use serde_json;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, RwLock, Mutex};
 use std::sync::mpsc::{channel, Sender};
 use std::path::{Path, PathBuf};
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::thread;
+use std::time::Duration;
 
 use fuse_mt::*;
+use acl::*;
+use attributes::*;
+use config::FsConfig;
+use connection::Connection;
 use filesystem::*;
+use handles::*;
+use isupport::*;
+use names::*;
+use numerics;
+use permissions::Mode;
+use queue::*;
+use state::*;
+use transport::Transport;
+
+// Maximum length of an IRC line, including the trailing CR-LF
+const LINE_LIMIT: usize = 512;
+
+// Servers prepend our ":nick!user@host " to messages they relay. We don't
+// know our user and host, so assume they're as long as they can usually be.
+const USERHOST_LIMIT: usize = 10 + 1 + 63;
+
+// Files in a channel's directory for its list modes
+const MODE_LISTS: &'static [(&'static str, char)] = &[
+    ("bans", 'b'),
+    ("excepts", 'e'),
+    ("invex", 'I'),
+];
+
+// IRCv3 capabilities requested before registration
+const CAPABILITIES: &'static [&'static str] = &[
+    "account-notify",
+    "account-tag",
+    "away-notify",
+    "batch",
+    "extended-join",
+    "message-tags",
+    "server-time",
+];
 
 pub struct IrcFs {
     fs: Arc<RwLock<Filesystem>>,
-    server: IrcServer,
+    server: Arc<Transport>,
     tx_to_fs: Mutex<Sender<FsControl>>,
+    handles: Handles,
+    queue: Arc<OutQueue>,
+    acl: Acl,
+    casemapping: Arc<RwLock<CaseMapping>>,
+    // The server's CHANTYPES, to tell channels from nicks when a directory is made
+    chantypes: Arc<RwLock<String>>,
+    truncate_receive: bool,
 }
 
 #[allow(unused_must_use)]
 impl IrcFs {
-    pub fn new(config: &Config, uid: u32, gid: u32) -> IrcResult<Self> {
+    pub fn new(config: &Config, fs_config: &FsConfig, uid: u32, gid: u32) -> IrcResult<Self> {
         let mut config = config.clone();
         config.version = Some(format!("ircfs {}", option_env!("CARGO_PKG_VERSION").unwrap_or("unknown version")));
         config.source = Some("https://github.com/sector-f/ircfs".to_owned());
 
-        let srv = IrcServer::from_config(config.clone())?;
+        let connection = Connection::open(&config, fs_config)?;
+        let mut irc_config = config.clone();
+        if let Some(port) = connection.local_port() {
+            irc_config.server = Some("127.0.0.1".to_owned());
+            irc_config.port = Some(port);
+            irc_config.use_ssl = Some(false);
+        }
+
+        let srv = IrcServer::from_config(irc_config)?;
+
+        IrcFs::with_transport(Arc::new(srv), &config, fs_config, uid, gid, connection.status())
+    }
 
+    // Builds the filesystem on top of an already-open connection
+    pub fn with_transport(server: Arc<Transport>, config: &Config, fs_config: &FsConfig,
+                          uid: u32, gid: u32, connection_status: &str) -> IrcResult<Self> {
         let (tx, rx) = channel();
 
-        let mut fs = Filesystem::new(uid, gid);
+        let attributes_file = fs_config.attributes_file.clone()
+            .or(config.server.as_ref().and_then(|server| default_attributes_file(server)));
+        let attributes = match attributes_file {
+            Some(file) => AttributeStore::load(file)?,
+            None => AttributeStore::new(),
+        };
+
+        let mut fs = Filesystem::new(uid, gid, fs_config.file_mode, fs_config.dir_mode, attributes);
 
         fs.mk_rw_file("/send").unwrap();
-        fs.mk_ro_file("/receive").unwrap();
+        if fs_config.truncate_receive {
+            fs.mk_rw_file("/receive").unwrap();
+        } else {
+            fs.mk_ro_file("/receive").unwrap();
+        }
         fs.mk_ro_file("/raw").unwrap();
+        fs.mk_ro_file("/connection").unwrap();
+        fs.mk_rw_file("/queue").unwrap();
+        fs.mk_dir("/isupport").unwrap();
+        fs.mk_dir("/whois").unwrap();
+
+        if let Some(&Node::F(ref file)) = fs.get("/connection") {
+            file.replace_data(connection_status.as_bytes());
+        }
 
         let filesystem = IrcFs {
             fs: Arc::new(RwLock::new(fs)),
-            server: srv,
+            server: server,
             tx_to_fs: Mutex::new(tx.clone()),
+            handles: Handles::new(),
+            queue: Arc::new(OutQueue::new(
+                config.max_messages_in_burst.unwrap_or(15),
+                Duration::from_secs(config.burst_window_length.unwrap_or(8) as u64),
+            )),
+            acl: Acl::new(uid, fs_config.acl.clone()),
+            casemapping: Arc::new(RwLock::new(CaseMapping::default())),
+            chantypes: Arc::new(RwLock::new(ISupport::new().chantypes().to_owned())),
+            truncate_receive: fs_config.truncate_receive,
         };
 
+        let server = filesystem.server.clone();
+        let tx_to_fs = tx.clone();
+        let queue = filesystem.queue.clone();
+        OutQueue::start(filesystem.queue.clone(), move |outgoing| {
+            let time = time::now();
+            let nickname = server.current_nickname().to_owned();
+
+            server.send_privmsg(&outgoing.target, &outgoing.text);
+
+            tx_to_fs.send(
+                FsControl::Message(
+                    outgoing.channel_path.join("receive"),
+                    format!("{} {}: {}\n",
+                        time.strftime("%T").unwrap(),
+                        &nickname,
+                        &outgoing.text,
+                    ).into_bytes(),
+                )
+            );
+            tx_to_fs.send(
+                FsControl::Message(
+                    outgoing.channel_path.join("messages.jsonl"),
+                    JsonMessage::new(
+                        None, &nickname, "PRIVMSG", Some(&outgoing.text)
+                    ).into_line(),
+                )
+            );
+            tx_to_fs.send(FsControl::Replace(PathBuf::from("/queue"), queue.listing().into_bytes()));
+        });
+
         let fs = filesystem.fs.clone();
+        let truncate_receive = fs_config.truncate_receive;
         thread::spawn(move || {
             for message in rx.iter() {
-                let mut fs = fs.write().unwrap();
+                // Only creating directories changes the tree; writing to a
+                // file just needs that file's own lock
                 match message {
                     FsControl::Message(ref path, ref data) => {
-                        if let Some(&mut Node::F(ref mut file)) = fs.get_mut(path) {
+                        if let Some(file) = shared_file(&fs, path) {
+                            file.insert_data(&data);
+                        }
+                    },
+                    FsControl::Routed(ref path, ref data) => {
+                        let file = shared_file(&fs, &path.join("receive"))
+                            .or_else(|| shared_file(&fs, Path::new("/receive")));
+                        if let Some(file) = file {
                             file.insert_data(&data);
                         }
                     },
+                    FsControl::Replace(ref path, ref data) => {
+                        if let Some(file) = shared_file(&fs, path) {
+                            file.replace_data(&data);
+                        }
+                    },
+                    FsControl::Status(ref path, ref data) => {
+                        if shared_file(&fs, path).is_none() {
+                            let mut fs = fs.write().unwrap();
+                            if let Some(parent) = path.parent() {
+                                fs.mk_parents(parent);
+                            }
+                            fs.mk_ro_file(path);
+                        }
+                        if let Some(file) = shared_file(&fs, path) {
+                            file.replace_data(&data);
+                        }
+                    },
+                    FsControl::Remove(ref path) => {
+                        fs.write().unwrap().remove(path);
+                    },
                     FsControl::CreateDir(ref path) => {
-                        fs.mk_parents(&path);
-                        fs.mk_ro_file(&path.join("receive"));
-                        fs.mk_rw_file(&path.join("send"));
+                        // Directories only ever come from channel_path, so
+                        // anything else is a bug or a hostile name
+                        if ! is_channel_dir(path) {
+                            continue;
+                        }
+
+                        mk_channel_dir(&mut fs.write().unwrap(), path, truncate_receive);
+                    },
+                    FsControl::CreateModeFiles(ref path) => {
+                        if ! is_channel_dir(path) {
+                            continue;
+                        }
+
+                        let mut fs = fs.write().unwrap();
+                        fs.mk_ro_file(&path.join("modes"));
+                        for &(name, _) in MODE_LISTS {
+                            fs.mk_rw_file(&path.join(name));
+                        }
                     }
                 }
             }
         });
 
-        let tx_to_fs = tx.clone();
-        let server = filesystem.server.clone();
+        let mut incoming = Incoming {
+            server: filesystem.server.clone(),
+            tx_to_fs: tx.clone(),
+            state: ServerState::new(),
+            batches: HashMap::new(),
+            casemapping: filesystem.casemapping.clone(),
+            chantypes: filesystem.chantypes.clone(),
+            isupport: ISupport::new(),
+            pending_lists: HashMap::new(),
+            whois: HashMap::new(),
+        };
         thread::spawn(move|| {
-            let root = Path::new("/");
+            let server = incoming.server.clone();
+            // A REQ is all or nothing, so ask for each capability separately
+            // in case the server lacks some of them
+            for capability in CAPABILITIES {
+                server.send(Command::CAP(None, CapSubCommand::REQ, None, Some(capability.to_string())).into());
+            }
             server.identify();
-            server.for_each_incoming(|msg| {
-                let time = time::now();
+            server.for_each_incoming(&mut |msg| incoming.handle(msg));
+        });
 
-                tx_to_fs.send(
-                    FsControl::Message(
-                        root.join("raw"),
-                        format!("{} {}",
-                            time.strftime("%T").unwrap(),
-                            msg,
-                        ).into_bytes(),
-                    )
-                );
+        if let Some(ref channels) = config.channels {
+            let tx = filesystem.tx_to_fs.lock().unwrap();
+            for channel in channels {
+                if let Some(path) = filesystem.channel_path(channel) {
+                    tx.send(FsControl::CreateDir(path));
+                }
+            }
+        }
 
-                let msg_clone = msg.clone();
-                match msg.command {
-                    Command::PRIVMSG(target, message) => {
-                        let username = msg_clone.source_nickname()
-                            .unwrap_or(server.current_nickname()).to_owned();
-                        let chan_path = if &target == server.current_nickname() {
-                            root.join(&username)
-                        } else {
-                            root.join(target)
-                        };
-                        tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
-                        tx_to_fs.send(
-                            FsControl::Message(
-                                chan_path.clone().join("receive"),
-                                format!("{} {}: {}\n",
-                                    time.strftime("%T").unwrap(),
-                                    &username,
-                                    message.trim(),
-                                ).into_bytes(),
-                            )
-                        );
+        return Ok(filesystem);
+    }
+
+    fn channel_path(&self, name: &str) -> Option<PathBuf> {
+        channel_path(*self.casemapping.read().unwrap(), name)
+    }
+
+    fn is_channel(&self, target: &str) -> bool {
+        target.starts_with(|c| self.chantypes.read().unwrap().contains(c))
+    }
+
+    // Whether the ACL lets the requester perform an operation on the channel a path belongs to
+    fn acl_allows(&self, req: &RequestInfo, path: &Path, is_dir: bool, operation: Operation) -> bool {
+        let channel = channel_of(path, is_dir);
+        self.acl.allows(req, channel.as_ref().map(|c| c.as_str()), operation, *self.casemapping.read().unwrap())
+    }
+
+    // Handles data written to a send file: commands for /send, messages otherwise.
+    // Each line is handled separately.
+    fn submit(&self, path: &Path, data: Vec<u8>) {
+        if let Ok(string) = String::from_utf8(data) {
+            for line in string.lines() {
+                let line = line.trim_end();
+                if ! line.is_empty() {
+                    self.submit_line(path, line);
+                }
+            }
+        }
+    }
+
+    fn submit_line(&self, path: &Path, line: &str) {
+        if let Some(file) = shared_file(&self.fs, path) {
+            file.insert_data(format!("{}\n", line).as_bytes());
+        }
+
+        if path == Path::new("/send") {
+            let sections = line.split(' ').collect::<Vec<_>>();
+            if let Some(command) = sections.iter().skip_while(|s| s.is_empty()).nth(0) {
+                let arguments = sections.iter().skip_while(|s| s.is_empty()).skip(1).skip_while(|s| s.is_empty()).map(|s| s.to_owned().trim()).collect::<Vec<_>>();
+                match *command {
+                    "/j" | "/join" | "j" | "join" => {
+                        if arguments.len() == 1 {
+                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
+                            for chan in arguments[0].split(',') {
+                                if let Some(channel_path) = self.channel_path(chan) {
+                                    tx_to_fs.send(FsControl::CreateDir(channel_path));
+                                    self.server.send_join(&chan);
+                                }
+                            }
+                        } else if arguments.len() > 1 {
+                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
+                            for (chan, key) in arguments[0].split(',').zip(arguments[1].split(',')) {
+                                if let Some(channel_path) = self.channel_path(chan) {
+                                    tx_to_fs.send(FsControl::CreateDir(channel_path));
+                                    self.server.send_join_with_keys(&chan, &key);
+                                }
+                            }
+                        }
                     },
-                    Command::JOIN(channel, _, _) => {
-                        let username = msg_clone.source_nickname()
-                            .unwrap_or(server.current_nickname()).to_owned();
-                        let chan_path = root.join(&channel);
-                        tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
-                        tx_to_fs.send(
-                            FsControl::Message(
-                                chan_path.clone().join("receive"),
-                                format!("{} {} has joined\n",
-                                    time.strftime("%T").unwrap(),
-                                    &username,
-                                ).into_bytes(),
-                            )
-                        );
+                    "/whois" | "whois" => {
+                        for nick in arguments.iter().flat_map(|a| a.split(',')).filter(|n| !n.is_empty()) {
+                            self.server.send(Message::from(Command::WHOIS(None, nick.to_owned())));
+                        }
+                    },
+                    "/part" | "part" => {
+                        if arguments.len() == 1 {
+                            for chan in arguments[0].split(',') {
+                                self.server.send(Message::from(Command::PART(String::from(chan), None)));
+                            }
+                        } else if arguments.len() > 1 {
+                            for (chan, reason) in arguments[0].split(',').zip(arguments[1].split(',')) {
+                                let r = if reason.is_empty() { None } else { Some(reason.to_owned()) };
+                                self.server.send(Message::from(Command::PART(String::from(chan), r)));
+                            }
+                        }
                     },
-                    Command::PART(channel, reason) => {
-                        let username = msg_clone.source_nickname()
-                            .unwrap_or(server.current_nickname()).to_owned();
-                        let chan_path = root.join(&channel);
-
-                        let reason = if let Some(r) = reason {
-                            format!(" ({})", r)
-                        } else {
-                            "".to_string()
+                    "/msg" | "msg" => {
+                        let channel_path = match arguments.get(0).and_then(|target| self.channel_path(target)) {
+                            Some(channel_path) => channel_path,
+                            None => return,
                         };
 
-                        tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
-                        tx_to_fs.send(
-                            FsControl::Message(
-                                chan_path.clone().join("receive"),
-                                format!("{} {} has left{}\n",
-                                    time.strftime("%T").unwrap(),
-                                    &username,
-                                    &reason,
-                                ).into_bytes(),
-                            )
-                        );
-                    },
-                    Command::PING(_, _) => {},
-                    _ => {
-                        tx_to_fs.send(
-                            FsControl::Message(
-                                root.join("receive"),
-                                format!("{} {}",
-                                    time.strftime("%T").unwrap(),
-                                    msg,
-                                ).into_bytes(),
-                            )
-                        );
+                        if arguments.len() == 1 {
+                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
+                            tx_to_fs.send(FsControl::CreateDir(channel_path.clone()));
+                        } else if arguments.len() > 1 {
+                            self.tx_to_fs.lock().unwrap().send(FsControl::CreateDir(channel_path.clone()));
+
+                            let message = arguments.iter().skip(1).map(|s| s.to_owned()).collect::<Vec<&str>>().join(" ");
+                            self.send_message(arguments[0], &channel_path, &message);
+                        }
                     },
+                    _ => {},
                 }
-            });
-        });
-
-        if let Some(ref channels) = config.channels {
-            let tx = filesystem.tx_to_fs.lock().unwrap();
-            for channel in channels {
-                let path = Path::new("/").join(channel);
-                tx.send(FsControl::CreateDir(path));
             }
+        } else {
+            let channel_dir = path.parent().unwrap().to_owned();
+            let channel = decode_name(&channel_dir.file_name().unwrap().to_string_lossy());
+
+            self.send_message(&channel, &channel_dir, line);
         }
+    }
 
-        return Ok(filesystem);
+    // Sends the MODE changes that turn a list file's current contents (e.g.
+    // the channel's bans) into the wanted ones
+    fn apply_list(&self, path: &Path, current: &[u8], wanted: &[u8]) {
+        let mode = match list_mode(path) {
+            Some(mode) => mode,
+            None => return,
+        };
+        let channel = match path.parent().and_then(|dir| dir.file_name()) {
+            Some(name) => decode_name(&name.to_string_lossy()),
+            None => return,
+        };
+
+        let current = list_masks(current);
+        let wanted = list_masks(wanted);
+
+        for mask in wanted.difference(&current) {
+            self.server.send(Command::Raw("MODE".to_owned(), vec![channel.clone(), format!("+{}", mode), mask.to_owned()], None).into());
+        }
+        for mask in current.difference(&wanted) {
+            self.server.send(Command::Raw("MODE".to_owned(), vec![channel.clone(), format!("-{}", mode), mask.to_owned()], None).into());
+        }
+    }
+
+    // Queues a PRIVMSG, split up as needed to fit within the protocol's line length limit
+    fn send_message(&self, target: &str, channel_path: &Path, message: &str) {
+        let nickname = self.server.current_nickname().to_owned();
+
+        for chunk in split_utf8(message, max_message_len(&nickname, target)) {
+            self.queue.push(
+                Outgoing {
+                    target: target.to_owned(),
+                    channel_path: channel_path.to_owned(),
+                    text: chunk.to_owned(),
+                }
+            );
+        }
+
+        self.tx_to_fs.lock().unwrap().send(
+            FsControl::Replace(PathBuf::from("/queue"), self.queue.listing().into_bytes())
+        );
     }
 }
 
@@ -173,57 +411,149 @@ impl FilesystemMT for IrcFs {
         Ok(())
     }
 
-    fn read(&self, _req:RequestInfo, path:&Path, _fh:u64, offset:u64, size:u32) -> ResultData {
-        let fs = self.fs.read().unwrap();
+    fn read(&self, req:RequestInfo, path:&Path, fh:u64, offset:u64, size:u32) -> ResultData {
+        let file = {
+            let fs = self.fs.read().unwrap();
 
-        match fs.get(path) {
-            Some(&Node::D(ref _dir)) => {
-                Err(EISDIR)
-            },
-            Some(&Node::F(ref file)) => {
-                let data = file.data();
+            if ! fs.can_access(path, &req) {
+                return Err(EACCES);
+            }
 
-                if offset >= data.len() as u64 {
-                    Ok(Vec::new())
-                } else {
-                    let end = {
-                        if (size as u64 + offset) as usize > data.len() {
-                            data.len()
-                        } else {
-                            size as usize
-                        }
-                    };
+            match fs.get(path) {
+                Some(&Node::D(ref _dir)) => {
+                    return Err(EISDIR);
+                },
+                Some(&Node::F(ref file)) => {
+                    file.clone()
+                },
+                None => {
+                    return Err(ENOENT);
+                },
+            }
+        };
 
-                    if offset >= end as u64 {
-                        Ok(Vec::new())
-                    } else {
-                        Ok(data[offset as usize..end].to_owned())
+        let attr = file.attr();
+        if ! can_read(attr.uid, attr.gid, attr.perm, &req)
+            || ! self.acl_allows(&req, path, false, Operation::Read) {
+            return Err(EACCES);
+        }
+
+        let result = file.read_at(offset, size);
+
+        self.handles.with(fh, |handle| {
+            if let Handle::Receive(ref mut cursor) = *handle {
+                *cursor = offset + result.len() as u64;
+            }
+        });
+
+        Ok(result)
+    }
+
+    // Truncating a send file discards its history, and truncating a receive file
+    // (if enabled with truncate_receive) discards its scrollback. This is also
+    // what happens when a file is opened with O_TRUNC, e.g. with `>` in a shell.
+    fn truncate(&self,req:RequestInfo,path:&Path,fh:Option<u64>,size:u64) -> ResultEmpty {
+        // The tree is only locked while checking permissions, so that sending
+        // changes to mode lists doesn't hold it up
+        let file = {
+            let fs = self.fs.read().unwrap();
+
+            if ! fs.can_access(path, &req) {
+                return Err(EACCES);
+            }
+
+            match fs.get(path) {
+                Some(&Node::D(ref _dir)) => {
+                    return Err(EISDIR);
+                },
+                Some(&Node::F(ref file)) => {
+                    let attr = file.attr();
+                    let uid = attr.uid;
+                    let gid = attr.gid;
+                    let mode = attr.perm;
+
+                    if ! can_write(uid, gid, mode, &req)
+                        || ! self.acl_allows(&req, path, false, write_operation(path)) {
+                        return Err(EACCES);
                     }
-                }
-            },
-            None => {
-                Err(ENOENT)
-            },
+
+                    file.clone()
+                },
+                None => {
+                    return Err(ENOENT);
+                },
+            }
+        };
+
+        if path == Path::new("/queue") {
+            // Truncating the queue cancels any messages that haven't been sent yet
+            if size == 0 {
+                self.queue.clear();
+                file.truncate(0);
+            }
+        } else if list_mode(path).is_some() {
+            // The list itself only changes once the server confirms it
+            match fh {
+                Some(fh) => {
+                    self.handles.with(fh, |handle| handle.truncate(size));
+                },
+                None => {
+                    let current = file.contents();
+                    let wanted = &current[..(size as usize).min(current.len())];
+                    self.apply_list(path, &current, wanted);
+                },
+            }
+        } else {
+            file.truncate(size);
         }
+
+        Ok(())
     }
 
-    fn truncate(&self,req:RequestInfo,path:&Path,_fh:Option<u64>,_size:u64) -> ResultEmpty {
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         let fs = self.fs.read().unwrap();
 
+        if ! fs.can_access(path, &req) {
+            return Err(EACCES);
+        }
+
         match fs.get(path) {
             Some(&Node::D(ref _dir)) => {
                 Err(EISDIR)
             },
             Some(&Node::F(ref file)) => {
-                let uid = file.attr.uid;
-                let gid = file.attr.gid;
-                let mode = file.attr.perm;
+                let attr = file.attr();
+                let uid = attr.uid;
+                let gid = attr.gid;
+                let mode = attr.perm;
 
-                if can_write(uid, gid, mode, &req) {
-                    Ok(())
-                } else {
-                    Err(ENOTSUP)
+                let reading = self.acl_allows(&req, path, false, Operation::Read)
+                    && can_read(uid, gid, mode, &req);
+                let writing = self.acl_allows(&req, path, false, write_operation(path))
+                    && can_write(uid, gid, mode, &req);
+
+                let access_mode = flags as i32 & O_ACCMODE;
+                let allowed = match access_mode {
+                    O_RDONLY => reading,
+                    O_WRONLY => writing,
+                    _ => reading && writing,
+                };
+                if ! allowed {
+                    return Err(EACCES);
                 }
+
+                let handle = if path.file_name() == Some(OsStr::new("send")) {
+                    Handle::Send(Vec::new())
+                } else if list_mode(path).is_some() && access_mode != O_RDONLY {
+                    if flags as i32 & O_TRUNC != 0 {
+                        Handle::List(Vec::new())
+                    } else {
+                        Handle::List(file.contents())
+                    }
+                } else {
+                    Handle::Receive(0)
+                };
+                Ok((self.handles.insert(handle), 0))
             },
             None => {
                 Err(ENOENT)
@@ -231,168 +561,1719 @@ impl FilesystemMT for IrcFs {
         }
     }
 
-    fn write(&self, req: RequestInfo, path: &Path, _fh: u64, _offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
-        let mut fs = self.fs.write().unwrap();
-        let time = time::now();
+    fn write(&self, req: RequestInfo, path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+        {
+            let fs = self.fs.read().unwrap();
 
-        match fs.get_mut(path) {
-            Some(&mut Node::D(ref mut _dir)) => {
-                Err(EISDIR)
-            },
-            Some(&mut Node::F(ref mut file)) => {
-                let uid = file.attr.uid;
-                let gid = file.attr.gid;
-                let mode = file.attr.perm;
-                let len = data.len();
-
-                if can_write(uid, gid, mode, &req) {
-                    if let Ok(mut string) = String::from_utf8(data) {
-                        let trimmed_len = string.trim_right().len();
-                        string.truncate(trimmed_len);
-                        if string.is_empty() {
-                            return Ok(len as u32);
-                        }
-                        string.push('\n');
-                        file.insert_data(string.as_bytes());
-                        let _ = string.pop();
-
-                        if path == Path::new("/send") {
-                            let sections = string.split(' ').collect::<Vec<_>>();
-                            if let Some(command) = sections.iter().skip_while(|s| s.is_empty()).nth(0) {
-                                let arguments = sections.iter().skip_while(|s| s.is_empty()).skip(1).skip_while(|s| s.is_empty()).map(|s| s.to_owned().trim()).collect::<Vec<_>>();
-                                match *command {
-                                    "/j" | "/join" | "j" | "join" => {
-                                        if arguments.len() == 1 {
-                                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
-                                            for chan in arguments[0].split(',') {
-                                                let channel_path = Path::new("/").join(chan.clone());
-                                                tx_to_fs.send(FsControl::CreateDir(channel_path.clone()));
-
-                                                self.server.send_join(&chan);
-                                            }
-                                        } else if arguments.len() > 1 {
-                                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
-                                            for (chan, key) in arguments[0].split(',').zip(arguments[1].split(',')) {
-                                                let channel_path = Path::new("/").join(chan.clone());
-                                                tx_to_fs.send(FsControl::CreateDir(channel_path.clone()));
-
-                                                self.server.send_join_with_keys(&chan, &key);
-                                            }
-                                        }
-                                    },
-                                    "/part" | "part" => {
-                                        if arguments.len() == 1 {
-                                            for chan in arguments[0].split(',') {
-                                                self.server.send(Message::from(Command::PART(String::from(chan), None)));
-                                            }
-                                        } else if arguments.len() > 1 {
-                                            for (chan, reason) in arguments[0].split(',').zip(arguments[1].split(',')) {
-                                                let r = if reason.is_empty() { None } else { Some(reason.to_owned()) };
-                                                self.server.send(Message::from(Command::PART(String::from(chan), r)));
-                                            }
-                                        }
-                                    },
-                                    "/msg" | "msg" => {
-                                        if arguments.len() == 1 {
-                                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
-                                            let channel_path = Path::new("/").join(arguments[0].clone());
-                                            tx_to_fs.send(FsControl::CreateDir(channel_path.clone()));
-                                        } else if arguments.len() > 1 {
-                                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
-                                            let channel_path = Path::new("/").join(arguments[0].clone());
-                                            tx_to_fs.send(FsControl::CreateDir(channel_path.clone()));
-
-                                            let message = arguments.iter().skip(1).map(|s| s.to_owned()).collect::<Vec<&str>>().join(" ");
-                                            self.server.send_privmsg(arguments[0], &message);
-                                            tx_to_fs.send(
-                                                FsControl::Message(
-                                                    channel_path.join("receive"),
-                                                    format!("{} {}: {}\n",
-                                                        time.strftime("%T").unwrap(),
-                                                        self.server.current_nickname(),
-                                                        message,
-                                                    ).into_bytes(),
-                                                )
-                                            );
-                                        }
-                                    },
-                                    _ => {},
-                                }
-                            }
-                        } else {
-                            let channel_dir = PathBuf::from(&path).parent().unwrap().to_owned();
-                            let channel = channel_dir.file_name().unwrap();
+            if ! fs.can_access(path, &req) {
+                return Err(EACCES);
+            }
 
-                            self.server.send_privmsg(&channel.to_string_lossy(), &string);
+            match fs.get(path) {
+                Some(&Node::D(ref _dir)) => {
+                    return Err(EISDIR);
+                },
+                Some(&Node::F(ref file)) => {
+                    let attr = file.attr();
+                    let uid = attr.uid;
+                    let gid = attr.gid;
+                    let mode = attr.perm;
 
-                            let tx_to_fs = self.tx_to_fs.lock().unwrap();
-                            tx_to_fs.send(
-                                FsControl::Message(
-                                    channel_dir.clone().join("receive"),
-                                    format!("{} {}: {}\n",
-                                        time.strftime("%T").unwrap(),
-                                        self.server.current_nickname(),
-                                        string.trim(),
-                                    ).into_bytes(),
-                                )
-                            );
-                        }
+                    if ! can_write(uid, gid, mode, &req)
+                        || ! self.acl_allows(&req, path, false, write_operation(path)) {
+                        return Err(EACCES);
+                    }
+
+                    // Other writable files (e.g. the queue) can only be truncated
+                    if path.file_name() != Some(OsStr::new("send")) && list_mode(path).is_none() {
+                        return Err(EINVAL);
                     }
+                },
+                None => {
+                    return Err(ENOENT);
+                },
+            }
+        }
+
+        let len = data.len();
+
+        // Changes to mode lists are applied all at once when the file is closed
+        if list_mode(path).is_some() {
+            return match self.handles.with(fh, |handle| handle.write_at(offset, &data)) {
+                Some(Err(e)) => Err(e),
+                _ => Ok(len as u32),
+            };
+        }
+
+        // Only complete lines are submitted; anything after the last newline
+        // waits in the handle until more data arrives or the file is closed
+        let complete = self.handles.with(fh, |handle| handle.push_input(&data)).unwrap_or(data);
+        if ! complete.is_empty() {
+            self.submit(path, complete);
+        }
+
+        Ok(len as u32)
+    }
+
+    fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
+        let rest = self.handles.with(fh, |handle| handle.take_input()).unwrap_or_default();
+        if ! rest.is_empty() {
+            self.submit(path, rest);
+        }
+
+        Ok(())
+    }
+
+    fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        if let Some(mut handle) = self.handles.remove(fh) {
+            if let Handle::List(ref contents) = handle {
+                let current = shared_file(&self.fs, path).map(|file| file.contents()).unwrap_or_default();
+                self.apply_list(path, &current, contents);
+            }
+
+            let rest = handle.take_input();
+            if ! rest.is_empty() {
+                self.submit(path, rest);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        let fs = self.fs.read().unwrap();
+
+        if ! fs.can_access(path, &req) {
+            return Err(EACCES);
+        }
+
+        match fs.get(path) {
+            Some(&Node::D(ref dir)) => {
+                // Everyone may list the top level, which only shows them the channels they can read
+                let allowed = path == Path::new("/")
+                    || self.acl_allows(&req, path, true, Operation::Read);
 
-                    Ok(len as u32)
+                if allowed && can_read(dir.attr.uid, dir.attr.gid, dir.attr.perm, &req) {
+                    Ok((self.handles.insert(Handle::Dir), 0))
                 } else {
-                    // Should probably be changed to EACCES if/when permissions are implemented
-                    // But, currently, this will just be the "receive" files, and ENOTSUP seems
-                    // more logical
-                    Err(ENOTSUP)
+                    Err(EACCES)
                 }
             },
+            Some(&Node::F(ref _file)) => {
+                Err(ENOTDIR)
+            },
             None => {
                 Err(ENOENT)
             },
         }
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        let fs = self.fs.read().unwrap();
-
-        if let Some(_node) = fs.get(path) {
-            Ok((0, 0))
-        } else {
-            Err(ENOENT)
-        }
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+        self.handles.remove(fh);
+        Ok(())
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
-        let fs = self.fs.read().unwrap();
+    // Making a directory at the top level joins that channel (or, for a nick,
+    // opens a conversation), like "/join" does
+    fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+        let path = parent.join(name);
+        let target = decode_name(&name.to_string_lossy());
 
-        if let Some(node) = fs.get(path) {
-            Ok((Timespec::new(1, 0), node.attr().clone()))
-        } else {
-            Err(ENOENT)
+        let attr = {
+            let mut fs = self.fs.write().unwrap();
+
+            if parent != Path::new("/") {
+                return Err(EPERM);
+            }
+            if ! fs.can_access(&path, &req) {
+                return Err(EACCES);
+            }
+            if fs.get(&path).is_some() {
+                return Err(EEXIST);
+            }
+
+            let attr = fs.get(parent).unwrap().attr();
+            if ! can_write(attr.uid, attr.gid, attr.perm, &req)
+                || ! self.acl_allows(&req, parent, true, Operation::Command) {
+                return Err(EACCES);
+            }
+
+            // The name has to be the one the directory would get anyway, i.e.
+            // already encoded and in the server's case
+            if self.channel_path(&target).as_ref() != Some(&path) {
+                return Err(EINVAL);
+            }
+
+            mk_channel_dir(&mut fs, &path, self.truncate_receive);
+            match fs.get(&path) {
+                Some(node) => node.attr(),
+                None => return Err(EIO),
+            }
+        };
+
+        if self.is_channel(&target) {
+            self.server.send_join(&target);
         }
+
+        Ok((Timespec::new(1, 0), attr))
     }
 
-    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
-        let fs = self.fs.read().unwrap();
+    // Removing a directory leaves the channel, and discards its logs
+    fn rmdir(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        let path = parent.join(name);
+        let target = decode_name(&name.to_string_lossy());
 
-        match fs.dir_entries(&path) {
-            Some(mut entries) => {
-                entries.push(
-                    DirectoryEntry {name: OsString::from("."), kind: FileType::Directory}
-                );
-                entries.push(
-                    DirectoryEntry {name: OsString::from(".."), kind: FileType::Directory}
-                );
-                Ok(entries)
-            },
-            None => Err(ENOENT),
+        {
+            let mut fs = self.fs.write().unwrap();
+
+            if ! fs.can_access(&path, &req) {
+                return Err(EACCES);
+            }
+            match fs.get(&path) {
+                Some(&Node::D(_)) => {},
+                Some(&Node::F(_)) => return Err(ENOTDIR),
+                None => return Err(ENOENT),
+            }
+            // e.g. /whois
+            if ! is_channel_dir(&path) {
+                return Err(EPERM);
+            }
+
+            let attr = fs.get(parent).unwrap().attr();
+            if ! can_write(attr.uid, attr.gid, attr.perm, &req)
+                || ! self.acl_allows(&req, parent, true, Operation::Command) {
+                return Err(EACCES);
+            }
+
+            fs.remove(&path).map_err(|_| EIO)?;
+        }
+
+        if self.is_channel(&target) {
+            self.server.send(Message::from(Command::PART(target, None)));
         }
+
+        Ok(())
     }
-}
 
-#[derive(Debug, Clone)]
-enum FsControl {
-    CreateDir(PathBuf),
-    Message(PathBuf, Vec<u8>),
+    fn chmod(&self, req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+        let attributes = {
+            let mut fs = self.fs.write().unwrap();
+
+            if ! fs.can_access(path, &req) {
+                return Err(EACCES);
+            }
+
+            match fs.get(path) {
+                Some(node) => {
+                    if req.uid != 0 && req.uid != node.attr().uid {
+                        return Err(EPERM);
+                    }
+                },
+                None => {
+                    return Err(ENOENT);
+                },
+            }
+
+            let mode = Mode::new((mode & 0o7777) as u16).map_err(|_| EINVAL)?;
+            fs.set_mode(path, mode).map_err(|_| EIO)?
+        };
+
+        // Written out once the tree is unlocked, so that disk I/O doesn't
+        // hold up every other operation
+        attributes.save().map_err(|_| EIO)
+    }
+
+    fn chown(&self, req: RequestInfo, path: &Path, _fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        let attributes = {
+            let mut fs = self.fs.write().unwrap();
+
+            if ! fs.can_access(path, &req) {
+                return Err(EACCES);
+            }
+
+            // As with chown(2), only root can give a node away, and the owner can
+            // only change its group to one they're a member of
+            match fs.get(path) {
+                Some(node) => {
+                    let attr = node.attr();
+                    if req.uid != 0 {
+                        if req.uid != attr.uid || uid.map(|uid| uid != attr.uid).unwrap_or(false) {
+                            return Err(EPERM);
+                        }
+                        if gid.map(|gid| gid != attr.gid && ! in_group(gid, &req)).unwrap_or(false) {
+                            return Err(EPERM);
+                        }
+                    }
+                },
+                None => {
+                    return Err(ENOENT);
+                },
+            }
+
+            fs.set_owner(path, uid, gid).map_err(|_| EIO)?
+        };
+
+        attributes.save().map_err(|_| EIO)
+    }
+
+    fn access(&self, req: RequestInfo, path: &Path, mask: u32) -> ResultEmpty {
+        let fs = self.fs.read().unwrap();
+
+        if ! fs.can_access(path, &req) {
+            return Err(EACCES);
+        }
+
+        match fs.get(path) {
+            Some(node) => {
+                let attr = node.attr();
+                let mask = mask as i32;
+                let is_dir = attr.kind == FileType::Directory;
+
+                let can_list_or_read = (is_dir && path == Path::new("/"))
+                    || self.acl_allows(&req, path, is_dir, Operation::Read);
+
+                if (mask & R_OK != 0 && ! (can_read(attr.uid, attr.gid, attr.perm, &req) && can_list_or_read))
+                    || (mask & W_OK != 0 && ! (can_write(attr.uid, attr.gid, attr.perm, &req)
+                        && self.acl_allows(&req, path, is_dir, write_operation(path))))
+                    || (mask & X_OK != 0 && ! can_execute(attr.uid, attr.gid, attr.perm, &req)) {
+                    Err(EACCES)
+                } else {
+                    Ok(())
+                }
+            },
+            None => {
+                Err(ENOENT)
+            },
+        }
+    }
+
+    fn getattr(&self, req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        let fs = self.fs.read().unwrap();
+
+        if ! fs.can_access(path, &req) {
+            return Err(EACCES);
+        }
+
+        if let Some(node) = fs.get(path) {
+            Ok((Timespec::new(1, 0), node.attr()))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn readdir(&self, req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        let fs = self.fs.read().unwrap();
+
+        if ! fs.can_access(path, &req) {
+            return Err(EACCES);
+        }
+
+        if let Some(node) = fs.get(path) {
+            let attr = node.attr();
+            if ! can_read(attr.uid, attr.gid, attr.perm, &req) {
+                return Err(EACCES);
+            }
+        }
+
+        let is_root = path == Path::new("/");
+        if ! is_root && ! self.acl_allows(&req, path, true, Operation::Read) {
+            return Err(EACCES);
+        }
+
+        match fs.dir_entries(&path) {
+            Some(mut entries) => {
+                if is_root {
+                    entries.retain(|entry| {
+                        entry.kind != FileType::Directory
+                            || self.acl_allows(&req, &path.join(&entry.name), true, Operation::Read)
+                    });
+                }
+
+                entries.push(
+                    DirectoryEntry {name: OsString::from("."), kind: FileType::Directory}
+                );
+                entries.push(
+                    DirectoryEntry {name: OsString::from(".."), kind: FileType::Directory}
+                );
+                Ok(entries)
+            },
+            None => Err(ENOENT),
+        }
+    }
+}
+
+struct Incoming {
+    server: Arc<Transport>,
+    tx_to_fs: Sender<FsControl>,
+    state: ServerState,
+    batches: HashMap<String, Batch>,
+    casemapping: Arc<RwLock<CaseMapping>>,
+    chantypes: Arc<RwLock<String>>,
+    isupport: ISupport,
+    // Entries of list replies (e.g. RPL_BANLIST) seen before the end of the list
+    pending_lists: HashMap<(String, char), Vec<String>>,
+    // WHOIS replies seen before RPL_ENDOFWHOIS, by case-folded nick
+    whois: HashMap<String, numerics::Whois>,
+}
+
+#[allow(unused_must_use)]
+impl Incoming {
+    // Channels are tracked under their case-folded names
+    fn fold(&self, name: &str) -> String {
+        self.casemapping.read().unwrap().fold(name)
+    }
+
+    fn channel_path(&self, name: &str) -> Option<PathBuf> {
+        channel_path(*self.casemapping.read().unwrap(), name)
+    }
+
+    // Messages to a channel go in its directory, and anything else (i.e. a
+    // private message) in the sender's
+    fn conversation_path(&self, target: &str, sender: &str) -> Option<PathBuf> {
+        if self.isupport.is_channel(target) {
+            self.channel_path(target)
+        } else {
+            self.channel_path(sender)
+        }
+    }
+
+    fn update_mode_files(&self, channel: &str, chan_path: &Path) {
+        self.tx_to_fs.send(
+            FsControl::Replace(chan_path.join("modes"), self.state.mode_listing(channel).into_bytes())
+        );
+        for &(name, mode) in MODE_LISTS {
+            self.tx_to_fs.send(
+                FsControl::Replace(chan_path.join(name), self.state.list_listing(channel, mode).into_bytes())
+            );
+        }
+    }
+
+    // Fills in /whois/NICK once all of a WHOIS's replies have arrived
+    fn collect_whois(&mut self, code: u16, args: &[String], suffix: Option<&str>) {
+        let nick = match args.get(0) {
+            Some(nick) => self.fold(nick),
+            None => return,
+        };
+
+        match code {
+            // RPL_ENDOFWHOIS
+            318 => {
+                let whois = self.whois.remove(&nick).unwrap_or_default();
+                let whois_path = numerics::whois_path(&nick);
+
+                // Clear out anything left from an earlier WHOIS
+                self.tx_to_fs.send(FsControl::Remove(whois_path.clone()));
+                if whois.found() {
+                    for (name, contents) in whois.files() {
+                        self.tx_to_fs.send(FsControl::Status(whois_path.join(name), contents.into_bytes()));
+                    }
+                }
+            },
+            // ERR_NOSUCHNICK: anything collected so far is stale
+            401 => {
+                self.whois.remove(&nick);
+            },
+            // Other WHOIS replies (e.g. RPL_WHOISOPERATOR, RPL_WHOISSECURE)
+            // leave what's been collected alone
+            code if numerics::Whois::is_field(code) => {
+                self.whois.entry(nick).or_insert_with(numerics::Whois::default).update(code, args, suffix);
+            },
+            _ => {},
+        }
+    }
+
+    fn handle(&mut self, msg: Message) {
+        let time = time::now();
+
+        self.tx_to_fs.send(
+            FsControl::Message(
+                Path::new("/").join("raw"),
+                format!("{} {}",
+                    time.strftime("%T").unwrap(),
+                    msg,
+                ).into_bytes(),
+            )
+        );
+
+        if let Command::BATCH(ref reference, ref kind, ref params) = msg.command {
+            if reference.starts_with('+') {
+                let batch = Batch::new(kind.as_ref(), params.clone().unwrap_or_default());
+                self.batches.insert(reference[1..].to_owned(), batch);
+            } else if reference.starts_with('-') {
+                if let Some(batch) = self.batches.remove(&reference[1..]) {
+                    self.finish_batch(batch, &time.strftime("%T").unwrap().to_string());
+                }
+            }
+            return;
+        }
+
+        let mut stamp = time.strftime("%T").unwrap().to_string();
+
+        if let Some(batch) = batch_reference(&msg).and_then(|r| self.batches.get_mut(&r)) {
+            match batch.kind {
+                BatchKind::Netsplit | BatchKind::Netjoin => {
+                    batch.messages.push(msg);
+                    return;
+                },
+                BatchKind::Playback => {
+                    let time = server_time(&msg).unwrap_or(time);
+                    stamp = format!("{} [playback]", time.strftime("%T").unwrap());
+                },
+                BatchKind::Other => {},
+            }
+        }
+
+        self.dispatch(msg, &stamp);
+    }
+
+    // Collapses a netsplit/netjoin batch into one line per affected channel
+    fn finish_batch(&mut self, batch: Batch, stamp: &str) {
+        let mut affected: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for msg in batch.messages {
+            let username = msg.source_nickname()
+                .unwrap_or(self.server.current_nickname()).to_owned();
+
+            match (&batch.kind, &msg.command) {
+                (&BatchKind::Netsplit, &Command::QUIT(_)) => {
+                    for channel in self.state.quit(&username) {
+                        affected.entry(channel).or_insert_with(Vec::new).push(username.clone());
+                    }
+                },
+                (&BatchKind::Netjoin, &Command::JOIN(ref channel, ref account, _)) => {
+                    let channel = self.fold(channel);
+                    let account = account.clone().map(|a| if a == "*" { None } else { Some(a) });
+                    self.state.join(&channel, &username, account);
+                    affected.entry(channel).or_insert_with(Vec::new).push(username.clone());
+                },
+                _ => {
+                    self.dispatch(msg.clone(), stamp);
+                },
+            }
+        }
+
+        let description = match batch.kind {
+            BatchKind::Netsplit => "Netsplit",
+            _ => "Netsplit over",
+        };
+        let action = match batch.kind {
+            BatchKind::Netsplit => "quits",
+            _ => "joins",
+        };
+
+        for (channel, nicks) in affected {
+            let chan_path = match self.channel_path(&channel) {
+                Some(chan_path) => chan_path,
+                None => continue,
+            };
+
+            self.tx_to_fs.send(
+                FsControl::Message(
+                    chan_path.join("receive"),
+                    format!("{} {} {}, {}: {}\n",
+                        stamp,
+                        description,
+                        batch.params.join(" <-> "),
+                        action,
+                        nicks.join(", "),
+                    ).into_bytes(),
+                )
+            );
+            update_user_listing(&self.tx_to_fs, &self.state, &channel);
+        }
+    }
+
+    fn dispatch(&mut self, msg: Message, stamp: &str) {
+        let root = Path::new("/");
+
+        if let Command::Response(Response::RPL_NAMREPLY, ref args, Some(ref names)) = msg.command {
+            if let Some(channel) = args.last() {
+                let channel = self.fold(channel);
+                self.state.names(&channel, names.split(' '), &self.isupport.prefix_chars());
+                update_user_listing(&self.tx_to_fs, &self.state, &channel);
+            }
+        }
+
+        // Each token gets a file under /isupport
+        if let Command::Response(Response::RPL_ISUPPORT, ref args, _) = msg.command {
+            let isupport_dir = Path::new("/isupport");
+
+            // The first parameter is our nickname
+            for token in self.isupport.update(args.iter().skip(1)) {
+                match token {
+                    Token::Set(key, value) => {
+                        self.tx_to_fs.send(
+                            FsControl::Status(
+                                isupport_dir.join(encode_name(&key)),
+                                format!("{}\n", value.unwrap_or_default()).into_bytes(),
+                            )
+                        );
+                    },
+                    Token::Unset(key) => {
+                        self.tx_to_fs.send(FsControl::Remove(isupport_dir.join(encode_name(&key))));
+                    },
+                }
+            }
+
+            *self.casemapping.write().unwrap() = self.isupport.casemapping();
+            *self.chantypes.write().unwrap() = self.isupport.chantypes().to_owned();
+        }
+
+        // Replies about a nick or channel (WHOIS, errors) go next to the
+        // conversation they're about, if there is one
+        if let Some((code, args, suffix)) = numerics::numeric(&msg.command) {
+            self.collect_whois(code, args, suffix);
+
+            if let Some(route) = numerics::route(code, args, suffix) {
+                if let Some(chan_path) = self.channel_path(&route.target) {
+                    self.tx_to_fs.send(
+                        FsControl::Routed(
+                            chan_path,
+                            format!("{} {}\n", stamp, route.text).into_bytes(),
+                        )
+                    );
+                    return;
+                }
+            }
+        }
+
+        let msg_clone = msg.clone();
+        match msg.command {
+            Command::PRIVMSG(target, message) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let chan_path = match self.conversation_path(&target, &username) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+                self.tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.clone().join("receive"),
+                        format!("{} {}: {}\n",
+                            stamp,
+                            &username,
+                            message.trim(),
+                        ).into_bytes(),
+                    )
+                );
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("messages.jsonl"),
+                        JsonMessage::new(
+                            msg_clone.tags.as_ref(), &username, "PRIVMSG", Some(message.trim())
+                        ).into_line(),
+                    )
+                );
+            },
+            // Notices from users (rather than from the server itself)
+            Command::NOTICE(ref target, ref message) if is_from_user(&msg_clone) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let chan_path = match self.conversation_path(target, &username) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+                self.tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("receive"),
+                        format!("{} -{}- {}\n",
+                            stamp,
+                            &username,
+                            message.trim(),
+                        ).into_bytes(),
+                    )
+                );
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("messages.jsonl"),
+                        JsonMessage::new(
+                            msg_clone.tags.as_ref(), &username, "NOTICE", Some(message.trim())
+                        ).into_line(),
+                    )
+                );
+            },
+            // Messages that are only tags, like reactions (+draft/react) and
+            // replies (+draft/reply), have no text to show in receive
+            Command::Raw(ref command, ref args, _) if command == "TAGMSG" && !args.is_empty() => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let chan_path = match self.conversation_path(&args[0], &username) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+                self.tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("messages.jsonl"),
+                        JsonMessage::new(msg_clone.tags.as_ref(), &username, "TAGMSG", None).into_line(),
+                    )
+                );
+            },
+            Command::JOIN(channel, account, _) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let channel = self.fold(&channel);
+                let chan_path = match self.channel_path(&channel) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+                self.tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
+
+                if &username == self.server.current_nickname() {
+                    self.state.reset_channel(&channel);
+
+                    // Ask for the channel's modes and lists; the
+                    // replies fill in the files
+                    self.tx_to_fs.send(FsControl::CreateModeFiles(chan_path.clone()));
+                    self.server.send(Command::Raw("MODE".to_owned(), vec![channel.clone()], None).into());
+                    let list_modes = self.isupport.list_modes();
+                    for &(_, mode) in MODE_LISTS {
+                        if list_modes.contains(mode) {
+                            self.server.send(Command::Raw("MODE".to_owned(), vec![channel.clone(), format!("+{}", mode)], None).into());
+                        }
+                    }
+                }
+
+                // With extended-join, the account name is sent
+                // in place of the channel key ("*" if logged out)
+                let account = account.map(|a| if a == "*" { None } else { Some(a) });
+                self.state.join(&channel, &username, account);
+                update_user_listing(&self.tx_to_fs, &self.state, &channel);
+
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.clone().join("receive"),
+                        format!("{} {} has joined\n",
+                            stamp,
+                            &username,
+                        ).into_bytes(),
+                    )
+                );
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("messages.jsonl"),
+                        JsonMessage::new(
+                            msg_clone.tags.as_ref(), &username, "JOIN", None
+                        ).into_line(),
+                    )
+                );
+            },
+            Command::PART(channel, reason) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let channel = self.fold(&channel);
+                let chan_path = match self.channel_path(&channel) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+
+                self.tx_to_fs.send(FsControl::CreateDir(chan_path.clone()));
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("messages.jsonl"),
+                        JsonMessage::new(
+                            msg_clone.tags.as_ref(), &username, "PART", reason.as_ref().map(|r| r.as_str())
+                        ).into_line(),
+                    )
+                );
+
+                if &username == self.server.current_nickname() {
+                    self.state.leave_channel(&channel);
+                } else {
+                    self.state.part(&channel, &username);
+                }
+                update_user_listing(&self.tx_to_fs, &self.state, &channel);
+
+                let reason = if let Some(r) = reason {
+                    format!(" ({})", r)
+                } else {
+                    "".to_string()
+                };
+
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.clone().join("receive"),
+                        format!("{} {} has left{}\n",
+                            stamp,
+                            &username,
+                            &reason,
+                        ).into_bytes(),
+                    )
+                );
+            },
+            Command::KICK(channel, nick, comment) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let channel = self.fold(&channel);
+                let chan_path = match self.channel_path(&channel) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+
+                if &nick == self.server.current_nickname() {
+                    self.state.leave_channel(&channel);
+                } else {
+                    self.state.part(&channel, &nick);
+                }
+                update_user_listing(&self.tx_to_fs, &self.state, &channel);
+
+                let comment = if let Some(c) = comment {
+                    format!(" ({})", c)
+                } else {
+                    "".to_string()
+                };
+
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("receive"),
+                        format!("{} {} was kicked by {}{}\n",
+                            stamp,
+                            &nick,
+                            &username,
+                            &comment,
+                        ).into_bytes(),
+                    )
+                );
+            },
+            Command::QUIT(reason) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+
+                let channels = self.state.quit(&username);
+
+                let reason = if let Some(r) = reason {
+                    format!(" ({})", r)
+                } else {
+                    "".to_string()
+                };
+
+                for channel in channels {
+                    let chan_path = match self.channel_path(&channel) {
+                        Some(chan_path) => chan_path,
+                        None => continue,
+                    };
+                    self.tx_to_fs.send(
+                        FsControl::Message(
+                            chan_path.join("receive"),
+                            format!("{} {} has quit{}\n",
+                                stamp,
+                                &username,
+                                &reason,
+                            ).into_bytes(),
+                        )
+                    );
+                    update_user_listing(&self.tx_to_fs, &self.state, &channel);
+                }
+            },
+            Command::NICK(new_nick) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+
+                for channel in self.state.rename(&username, &new_nick) {
+                    let chan_path = match self.channel_path(&channel) {
+                        Some(chan_path) => chan_path,
+                        None => continue,
+                    };
+                    self.tx_to_fs.send(
+                        FsControl::Message(
+                            chan_path.join("receive"),
+                            format!("{} {} is now known as {}\n",
+                                stamp,
+                                &username,
+                                &new_nick,
+                            ).into_bytes(),
+                        )
+                    );
+                    update_user_listing(&self.tx_to_fs, &self.state, &channel);
+                }
+            },
+            Command::AWAY(message) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+
+                for channel in self.state.set_away(&username, message) {
+                    update_user_listing(&self.tx_to_fs, &self.state, &channel);
+                }
+            },
+            Command::ACCOUNT(account) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+
+                let (account, line) = if account == "*" {
+                    (None, format!("{} has logged out", &username))
+                } else {
+                    let line = format!("{} is now logged in as {}", &username, &account);
+                    (Some(account), line)
+                };
+
+                for channel in self.state.set_account(&username, account.clone()) {
+                    let chan_path = match self.channel_path(&channel) {
+                        Some(chan_path) => chan_path,
+                        None => continue,
+                    };
+                    self.tx_to_fs.send(
+                        FsControl::Message(
+                            chan_path.join("receive"),
+                            format!("{} {}\n",
+                                stamp,
+                                &line,
+                            ).into_bytes(),
+                        )
+                    );
+                    self.tx_to_fs.send(
+                        FsControl::Message(
+                            chan_path.join("messages.jsonl"),
+                            JsonMessage::new(
+                                msg_clone.tags.as_ref(), &username, "ACCOUNT", account.as_ref().map(|a| a.as_str())
+                            ).into_line(),
+                        )
+                    );
+                    update_user_listing(&self.tx_to_fs, &self.state, &channel);
+                }
+            },
+            Command::ChannelMODE(channel, modes) => {
+                let username = msg_clone.source_nickname()
+                    .unwrap_or(self.server.current_nickname()).to_owned();
+                let channel = self.fold(&channel);
+                let chan_path = match self.channel_path(&channel) {
+                    Some(chan_path) => chan_path,
+                    None => return,
+                };
+
+                let changes = modes.iter()
+                    .map(|mode| match *mode {
+                        IrcMode::Plus(ref mode, ref param) => ModeChange {
+                            set: true,
+                            mode: mode_char(mode),
+                            param: param.clone(),
+                        },
+                        IrcMode::Minus(ref mode, ref param) => ModeChange {
+                            set: false,
+                            mode: mode_char(mode),
+                            param: param.clone(),
+                        },
+                    })
+                    .collect::<Vec<_>>();
+
+                let status_modes = self.isupport.status_modes();
+                let channel_changes = changes.iter()
+                    .filter(|change| ! status_modes.contains(&change.mode))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                self.state.apply_modes(&channel, &channel_changes, &self.isupport.list_modes());
+                self.update_mode_files(&channel, &chan_path);
+
+                let description = changes.iter()
+                    .map(|change| format!("{}{}{}",
+                        if change.set { "+" } else { "-" },
+                        change.mode,
+                        change.param.as_ref().map(|p| format!(" {}", p)).unwrap_or_default(),
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        chan_path.join("receive"),
+                        format!("{} {} sets mode {}\n",
+                            stamp,
+                            &username,
+                            &description,
+                        ).into_bytes(),
+                    )
+                );
+            },
+            Command::Response(Response::RPL_CHANNELMODEIS, ref args, _) if args.len() >= 3 => {
+                let channel = self.fold(&args[1]);
+                if let Some(chan_path) = self.channel_path(&channel) {
+                    let changes = self.isupport.parse_modes(&args[2], &args[3..]);
+                    self.state.set_modes(&channel, &changes);
+                    self.update_mode_files(&channel, &chan_path);
+                }
+            },
+            Command::Response(Response::RPL_BANLIST, ref args, _)
+            | Command::Response(Response::RPL_EXCEPTLIST, ref args, _)
+            | Command::Response(Response::RPL_INVITELIST, ref args, _) if args.len() >= 3 => {
+                if let Some(mode) = list_reply_mode(&msg_clone.command) {
+                    let channel = self.fold(&args[1]);
+                    self.pending_lists.entry((channel, mode))
+                        .or_insert_with(Vec::new)
+                        .push(args[2].clone());
+                }
+            },
+            Command::Response(Response::RPL_ENDOFBANLIST, ref args, _)
+            | Command::Response(Response::RPL_ENDOFEXCEPTLIST, ref args, _)
+            | Command::Response(Response::RPL_ENDOFINVITELIST, ref args, _) if args.len() >= 2 => {
+                if let Some(mode) = list_reply_mode(&msg_clone.command) {
+                    let channel = self.fold(&args[1]);
+                    let masks = self.pending_lists.remove(&(channel.clone(), mode)).unwrap_or_default();
+                    self.state.set_list(&channel, mode, masks);
+                    if let Some(chan_path) = self.channel_path(&channel) {
+                        self.update_mode_files(&channel, &chan_path);
+                    }
+                }
+            },
+            Command::PING(_, _) => {},
+            _ => {
+                self.tx_to_fs.send(
+                    FsControl::Message(
+                        root.join("receive"),
+                        format!("{} {}",
+                            stamp,
+                            msg,
+                        ).into_bytes(),
+                    )
+                );
+            },
+        }
+    }
+}
+
+struct Batch {
+    kind: BatchKind,
+    params: Vec<String>,
+    messages: Vec<Message>,
+}
+
+enum BatchKind {
+    Netsplit,
+    Netjoin,
+    Playback,
+    Other,
+}
+
+impl Batch {
+    fn new(kind: Option<&BatchSubCommand>, params: Vec<String>) -> Self {
+        let kind = match kind {
+            Some(&BatchSubCommand::NETSPLIT) => BatchKind::Netsplit,
+            Some(&BatchSubCommand::NETJOIN) => BatchKind::Netjoin,
+            Some(&BatchSubCommand::CUSTOM(ref name)) => {
+                let name = name.to_lowercase();
+                if name == "chathistory" || name.ends_with("/chathistory") || name.ends_with("/playback") {
+                    BatchKind::Playback
+                } else {
+                    BatchKind::Other
+                }
+            },
+            None => BatchKind::Other,
+        };
+
+        Batch {
+            kind: kind,
+            params: params,
+            messages: Vec::new(),
+        }
+    }
+}
+
+fn tag_value<'a>(msg: &'a Message, key: &str) -> Option<&'a str> {
+    msg.tags.iter()
+        .flat_map(|tags| tags.iter())
+        .filter(|&&Tag(ref k, _)| k == key)
+        .filter_map(|&Tag(_, ref value)| value.as_ref().map(|v| v.as_str()))
+        .next()
+}
+
+fn batch_reference(msg: &Message) -> Option<String> {
+    tag_value(msg, "batch").map(|r| r.to_owned())
+}
+
+// Parses the IRCv3 server-time tag (e.g. "2017-06-25T14:12:53.123Z") into local time
+fn server_time(msg: &Message) -> Option<time::Tm> {
+    // Everything up to the seconds; anything that isn't ASCII there isn't a
+    // valid time anyway
+    let value = match tag_value(msg, "time").and_then(|value| value.get(..19)) {
+        Some(value) => value,
+        None => return None,
+    };
+
+    time::strptime(value, "%Y-%m-%dT%H:%M:%S").ok()
+        .map(|tm| time::at(tm.to_timespec()))
+}
+
+#[derive(Debug, Clone)]
+enum FsControl {
+    CreateDir(PathBuf),
+    Message(PathBuf, Vec<u8>),
+    // Appends to a directory's receive file, or to /receive if it doesn't exist
+    Routed(PathBuf, Vec<u8>),
+    Replace(PathBuf, Vec<u8>),
+    // Replaces the contents of a read-only file, creating it if needed
+    Status(PathBuf, Vec<u8>),
+    // The files only channels (not queries) have
+    CreateModeFiles(PathBuf),
+    Remove(PathBuf),
+}
+
+// The longest message that can be sent to target without being cut off
+fn max_message_len(nickname: &str, target: &str) -> usize {
+    // ":nick!user@host PRIVMSG target :message\r\n"
+    let overhead = 1 + nickname.len() + 1 + USERHOST_LIMIT + 1
+        + "PRIVMSG ".len() + target.len() + " :".len() + "\r\n".len();
+    LINE_LIMIT.saturating_sub(overhead)
+}
+
+// Splits s into chunks of at most max bytes, without splitting any characters
+fn split_utf8(s: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = s;
+
+    while rest.len() > max {
+        let mut end = max;
+        while ! rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map(|c| c.len_utf8()).unwrap_or(rest.len());
+        }
+
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    if ! rest.is_empty() {
+        chunks.push(rest);
+    }
+
+    chunks
+}
+
+// The list mode that a file in a channel's directory holds, e.g. 'b' for "bans"
+fn list_mode(path: &Path) -> Option<char> {
+    if path.parent() == Some(Path::new("/")) {
+        return None;
+    }
+
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+    MODE_LISTS.iter()
+        .find(|&&(file, _)| Some(file) == name.as_ref().map(|n| n.as_str()))
+        .map(|&(_, mode)| mode)
+}
+
+fn mode_char(mode: &ChannelMode) -> char {
+    mode.to_string().chars().next().unwrap_or('?')
+}
+
+// The list mode that a list reply (e.g. RPL_BANLIST) is for
+fn list_reply_mode(command: &Command) -> Option<char> {
+    match *command {
+        Command::Response(Response::RPL_BANLIST, _, _)
+        | Command::Response(Response::RPL_ENDOFBANLIST, _, _) => Some('b'),
+        Command::Response(Response::RPL_EXCEPTLIST, _, _)
+        | Command::Response(Response::RPL_ENDOFEXCEPTLIST, _, _) => Some('e'),
+        Command::Response(Response::RPL_INVITELIST, _, _)
+        | Command::Response(Response::RPL_ENDOFINVITELIST, _, _) => Some('I'),
+        _ => None,
+    }
+}
+
+// The masks in a list file, one per line
+fn list_masks(contents: &[u8]) -> BTreeSet<String> {
+    String::from_utf8_lossy(contents).lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|mask| mask.to_owned())
+        .collect()
+}
+
+// Creates a channel's (or query's) directory and the files every one has
+#[allow(unused_must_use)]
+fn mk_channel_dir(fs: &mut Filesystem, path: &Path, truncate_receive: bool) {
+    fs.mk_parents(path);
+    if truncate_receive {
+        fs.mk_rw_file(&path.join("receive"));
+    } else {
+        fs.mk_ro_file(&path.join("receive"));
+    }
+    fs.mk_ro_file(&path.join("messages.jsonl"));
+    fs.mk_ro_file(&path.join("users"));
+    fs.mk_rw_file(&path.join("send"));
+}
+
+// Clones a handle to the file at path, so that the tree lock can be released
+// before the file itself is locked
+fn shared_file(fs: &RwLock<Filesystem>, path: &Path) -> Option<FuseFile> {
+    match fs.read().unwrap().get(path) {
+        Some(&Node::F(ref file)) => Some(file.clone()),
+        _ => None,
+    }
+}
+
+fn update_user_listing(tx: &Sender<FsControl>, state: &ServerState, channel: &str) {
+    let _ = tx.send(
+        FsControl::Replace(
+            Path::new("/").join(encode_name(channel)).join("users"),
+            state.user_listing(channel).into_bytes(),
+        )
+    );
+}
+
+// Whether a message came from a user (nick!user@host) rather than a server
+fn is_from_user(msg: &Message) -> bool {
+    msg.prefix.as_ref().map(|prefix| prefix.contains('!')).unwrap_or(false)
+}
+
+// A single line of a channel's messages.jsonl file
+#[derive(Debug, Serialize)]
+struct JsonMessage {
+    timestamp: String,
+    nick: String,
+    account: Option<String>,
+    tags: BTreeMap<String, Option<String>>,
+    command: String,
+    text: Option<String>,
+}
+
+impl JsonMessage {
+    fn new(tags: Option<&Vec<Tag>>, nick: &str, command: &str, text: Option<&str>) -> Self {
+        let tags = tags.iter()
+            .flat_map(|tags| tags.iter())
+            .map(|&Tag(ref key, ref value)| (key.to_owned(), value.to_owned()))
+            .collect::<BTreeMap<_, _>>();
+
+        // Prefer the server-time tag so that the timestamp reflects when the
+        // server saw the message rather than when we processed it
+        let timestamp = match tags.get("time") {
+            Some(&Some(ref time)) => time.to_owned(),
+            _ => time::now_utc().rfc3339().to_string(),
+        };
+
+        let account = match tags.get("account") {
+            Some(&Some(ref account)) => Some(account.to_owned()),
+            _ => None,
+        };
+
+        JsonMessage {
+            timestamp: timestamp,
+            nick: nick.to_owned(),
+            account: account,
+            tags: tags,
+            command: command.to_owned(),
+            text: text.map(|s| s.to_owned()),
+        }
+    }
+
+    fn into_line(self) -> Vec<u8> {
+        let mut line = serde_json::to_vec(&self).unwrap();
+        line.push(b'\n');
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fuse_mt::{FilesystemMT, RequestInfo};
+    use irc::client::prelude::Message;
+    use libc::{self, EACCES, EEXIST, EINVAL, ENOENT, ENOTDIR, EPERM};
+    use time::{self, Timespec};
+
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::{self, Child, Command};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{max_message_len, server_time, split_utf8, CAPABILITIES, LINE_LIMIT, USERHOST_LIMIT};
+    use fake_server::TIMEOUT;
+    use testing::{Harness, NICK};
+
+    fn joined(channel: &str) -> Harness {
+        let harness = Harness::start();
+        harness.write("/send", &format!("/join {}\n", channel)).unwrap();
+        harness.server.expect(&format!("JOIN {}", channel));
+        harness.server.send(&format!(":{}!user@localhost JOIN {}", NICK, channel));
+        harness.wait_for_line(&format!("/{}/receive", channel), "has joined");
+        harness
+    }
+
+    #[test]
+    fn top_level_files() {
+        let harness = Harness::start();
+        assert_eq!(
+            harness.list("/").unwrap(),
+            vec![".", "..", "connection", "isupport", "queue", "raw", "receive", "send", "whois"],
+        );
+        assert!(harness.read("/connection").unwrap().ends_with("tls: no\n"));
+        assert_eq!(harness.read("/nothing"), Err(ENOENT));
+    }
+
+    #[test]
+    fn capabilities_are_requested_one_at_a_time() {
+        let harness = Harness::connect();
+        for capability in CAPABILITIES {
+            let line = harness.server.expect("CAP REQ ");
+            assert_eq!(line.trim_start_matches("CAP REQ ").trim_start_matches(':'), *capability);
+        }
+        harness.server.expect("CAP END");
+        harness.server.expect(&format!("NICK {}", NICK));
+    }
+
+    #[test]
+    fn join_command_creates_the_directory() {
+        let harness = Harness::start();
+        harness.write("/send", "/join #Test\n").unwrap();
+        harness.server.expect("JOIN #Test");
+
+        harness.wait_for_channel("#test");
+        assert_eq!(
+            harness.list("/#test").unwrap(),
+            vec![".", "..", "messages.jsonl", "receive", "send", "users"],
+        );
+    }
+
+    #[test]
+    fn mkdir_joins_and_rmdir_parts() {
+        let harness = Harness::start();
+        let (req, root) = (harness.req(), Path::new("/"));
+
+        harness.fs.mkdir(req, root, OsStr::new("#test"), 0o755).unwrap();
+        harness.server.expect("JOIN #test");
+        harness.wait_for_channel("#test");
+        assert_eq!(harness.fs.mkdir(req, root, OsStr::new("#test"), 0o755).unwrap_err(), EEXIST);
+
+        harness.server.send(&format!(":{}!user@localhost JOIN #test", NICK));
+        harness.wait_for_line("/#test/receive", "has joined");
+
+        harness.fs.rmdir(req, root, OsStr::new("#test")).unwrap();
+        harness.server.expect("PART #test");
+        assert!(! harness.exists("/#test"));
+        assert_eq!(harness.fs.rmdir(req, root, OsStr::new("#test")).unwrap_err(), ENOENT);
+    }
+
+    #[test]
+    fn mkdir_for_a_nick_opens_a_conversation() {
+        let harness = Harness::start();
+        let (req, root) = (harness.req(), Path::new("/"));
+
+        harness.fs.mkdir(req, root, OsStr::new("alice"), 0o755).unwrap();
+        harness.wait_for_channel("alice");
+        harness.write("/alice/send", "hi\n").unwrap();
+        harness.server.expect("PRIVMSG alice :hi");
+
+        // There's nothing to part, so the JOIN is the next of either
+        harness.fs.rmdir(req, root, OsStr::new("alice")).unwrap();
+        assert!(! harness.exists("/alice"));
+        harness.fs.mkdir(req, root, OsStr::new("#next"), 0o755).unwrap();
+        let line = loop {
+            let line = harness.server.expect("");
+            if line.starts_with("JOIN") || line.starts_with("PART") {
+                break line;
+            }
+        };
+        assert_eq!(line, "JOIN #next");
+    }
+
+    #[test]
+    fn only_channel_directories_can_be_made_and_removed() {
+        let harness = joined("#test");
+        let (req, root) = (harness.req(), Path::new("/"));
+
+        assert_eq!(harness.fs.mkdir(req, Path::new("/#test"), OsStr::new("sub"), 0o755).unwrap_err(), EPERM);
+        assert_eq!(harness.fs.mkdir(req, root, OsStr::new("whois"), 0o755).unwrap_err(), EEXIST);
+        // The server folds case, so this would end up somewhere else
+        assert_eq!(harness.fs.mkdir(req, root, OsStr::new("#Other"), 0o755).unwrap_err(), EINVAL);
+        assert_eq!(harness.fs.mkdir(req, root, OsStr::new("a%2fb"), 0o755).unwrap_err(), EINVAL);
+        assert!(! harness.exists("/#Other"));
+
+        assert_eq!(harness.fs.rmdir(req, root, OsStr::new("whois")).unwrap_err(), EPERM);
+        assert_eq!(harness.fs.rmdir(req, root, OsStr::new("send")).unwrap_err(), ENOTDIR);
+        assert_eq!(harness.fs.rmdir(req, Path::new("/#test"), OsStr::new("send")).unwrap_err(), ENOTDIR);
+        assert!(harness.exists("/#test/send"));
+    }
+
+    #[test]
+    fn joining_fills_in_the_channel() {
+        let harness = joined("#test");
+        harness.server.expect("MODE #test");
+        harness.wait_for_path("/#test/modes");
+        harness.wait_for_path("/#test/bans");
+
+        harness.server.send(&format!(":irc.test 353 {} = #test :{} @alice", NICK, NICK));
+        harness.server.send(&format!(":irc.test 366 {} #test :End of /NAMES list.", NICK));
+        harness.wait_for_line("/#test/users", "alice");
+
+        harness.server.send(":bob!b@localhost JOIN #test");
+        harness.wait_for_line("/#test/receive", "bob has joined");
+        harness.wait_for_line("/#test/users", "bob");
+        harness.wait_for_line("/#test/messages.jsonl", r#""command":"JOIN""#);
+    }
+
+    #[test]
+    fn channel_messages_go_to_the_channel() {
+        let harness = joined("#test");
+        harness.server.send(":alice!a@localhost PRIVMSG #test :hello there");
+
+        harness.wait_for_line("/#test/receive", "alice: hello there");
+        let line = harness.wait_for_line("/#test/messages.jsonl", "hello there");
+        assert!(line.contains(r#""nick":"alice""#));
+        assert!(line.contains(r#""command":"PRIVMSG""#));
+    }
+
+    #[test]
+    fn private_messages_go_to_the_sender() {
+        let harness = Harness::start();
+        harness.server.send(&format!(":Alice!a@localhost PRIVMSG {} :hi", NICK));
+
+        harness.wait_for_line("/alice/receive", "Alice: hi");
+        harness.server.send(&format!(":Alice!a@localhost NOTICE {} :psst", NICK));
+        harness.wait_for_line("/alice/receive", "-Alice- psst");
+    }
+
+    #[test]
+    fn server_notices_stay_at_the_top_level() {
+        let harness = Harness::start();
+        harness.server.send(&format!(":irc.test NOTICE {} :*** Looking up your hostname", NICK));
+
+        harness.wait_for_line("/receive", "Looking up your hostname");
+        harness.wait_for_line("/raw", "Looking up your hostname");
+        assert!(! harness.exists("/irc.test"));
+    }
+
+    #[test]
+    fn netsplits_are_collapsed() {
+        let harness = joined("#test");
+        harness.server.send(":alice!a@localhost JOIN #test");
+        harness.server.send(":bob!b@localhost JOIN #test");
+        harness.wait_for_line("/#test/users", "bob");
+
+        harness.server.send("BATCH +split netsplit irc.a.net irc.b.net");
+        harness.server.send("@batch=split :alice!a@localhost QUIT :irc.a.net irc.b.net");
+        harness.server.send("@batch=split :bob!b@localhost QUIT :irc.a.net irc.b.net");
+        harness.server.send("BATCH -split");
+        harness.wait_for_line("/#test/receive", "Netsplit irc.a.net <-> irc.b.net, quits: alice, bob");
+        assert_eq!(harness.read("/#test/users").unwrap(), format!("{} *\n", NICK));
+
+        harness.server.send("BATCH +join netjoin irc.a.net irc.b.net");
+        harness.server.send("@batch=join :alice!a@localhost JOIN #test");
+        harness.server.send("@batch=join :bob!b@localhost JOIN #test");
+        harness.server.send("BATCH -join");
+        harness.wait_for_line("/#test/receive", "Netsplit over irc.a.net <-> irc.b.net, joins: alice, bob");
+        harness.wait_for_line("/#test/users", "bob");
+
+        // None of them got a line of their own, apart from when they first joined
+        let receive = harness.read("/#test/receive").unwrap();
+        assert!(! receive.contains("has quit"), "{}", receive);
+        assert_eq!(receive.matches("alice has joined").count(), 1, "{}", receive);
+    }
+
+    #[test]
+    fn playback_is_marked_with_its_own_time() {
+        let harness = joined("#test");
+        harness.server.send("BATCH +history chathistory #test");
+        harness.server.send("@batch=history;time=2017-06-25T14:12:53.123Z :alice!a@localhost PRIVMSG #test :old news");
+        harness.server.send("BATCH -history");
+        harness.server.send(":alice!a@localhost PRIVMSG #test :new news");
+
+        let expected = time::at(Timespec::new(1498399973, 0)).strftime("%T").unwrap().to_string();
+        let line = harness.wait_for_line("/#test/receive", "old news");
+        assert_eq!(line, format!("{} [playback] alice: old news", expected));
+        let line = harness.wait_for_line("/#test/receive", "new news");
+        assert!(! line.contains("[playback]"), "{}", line);
+
+        let line = harness.wait_for_line("/#test/messages.jsonl", "old news");
+        assert!(line.contains(r#""timestamp":"2017-06-25T14:12:53.123Z""#), "{}", line);
+    }
+
+    #[test]
+    fn server_time_tags() {
+        let message = |time: &str| format!("@time={} :alice!a@localhost PRIVMSG #test :hi", time)
+            .parse::<Message>().unwrap();
+
+        let tm = server_time(&message("2017-06-25T14:12:53.123Z")).unwrap();
+        assert_eq!(tm.to_timespec().sec, 1498399973);
+        assert!(server_time(&message("2017-06-25T14:12:53Z")).is_some());
+
+        // Cutting at the seconds lands inside the "é"
+        assert!(server_time(&message("2017-06-25T14:12:5\u{e9}3Z")).is_none());
+        assert!(server_time(&message("\u{1F600}\u{1F600}\u{1F600}\u{1F600}\u{1F600}")).is_none());
+        assert!(server_time(&message("2017-06-25")).is_none());
+        assert!(server_time(&message("yesterday, around noon")).is_none());
+        assert!(server_time(&"PRIVMSG #test :hi".parse::<Message>().unwrap()).is_none());
+    }
+
+    #[test]
+    fn writing_to_a_channel_sends_to_it() {
+        let harness = joined("#test");
+        harness.write("/#test/send", "hello\nworld\n").unwrap();
+
+        harness.server.expect("PRIVMSG #test :hello");
+        harness.server.expect("PRIVMSG #test :world");
+        harness.wait_for_line("/#test/receive", &format!("{}: world", NICK));
+        assert_eq!(harness.read("/#test/send").unwrap(), "hello\nworld\n");
+    }
+
+    #[test]
+    fn split_utf8_keeps_characters_whole() {
+        assert_eq!(split_utf8("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(split_utf8("abcd", 4), vec!["abcd"]);
+        assert!(split_utf8("", 4).is_empty());
+
+        // "é" is two bytes and "€" three; a cut inside one moves back to its start
+        assert_eq!(split_utf8("abcé", 4), vec!["abc", "é"]);
+        assert_eq!(split_utf8("a€€", 5), vec!["a€", "€"]);
+        assert_eq!(split_utf8("€€€", 4), vec!["€", "€", "€"]);
+        assert_eq!(split_utf8("a\u{1F600}b", 4), vec!["a", "\u{1F600}", "b"]);
+
+        // Smaller than a character: each character goes on its own
+        assert_eq!(split_utf8("é€a", 1), vec!["é", "€", "a"]);
+        assert_eq!(split_utf8("é€a", 0), vec!["é", "€", "a"]);
+    }
+
+    #[test]
+    fn split_utf8_never_loses_anything() {
+        let text = "ümlauts, €uros and \u{1F600}s: ".repeat(40);
+        for max in 0..64 {
+            let chunks = split_utf8(&text, max);
+            assert_eq!(chunks.concat(), text, "max {}", max);
+            for chunk in &chunks {
+                assert!(chunk.len() <= max.max(4), "max {}: {:?}", max, chunk);
+                assert!(! chunk.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn max_message_len_leaves_room_for_the_prefix() {
+        let len = max_message_len("nick", "#channel");
+        let line = format!(":nick!{}@{} PRIVMSG #channel :{}\r\n",
+            "u".repeat(10), "h".repeat(USERHOST_LIMIT - 11), "x".repeat(len));
+        assert_eq!(line.len(), LINE_LIMIT);
+
+        assert_eq!(max_message_len("nick", &"#".repeat(LINE_LIMIT)), 0);
+    }
+
+    #[test]
+    fn long_messages_are_split() {
+        let harness = joined("#test");
+        let text = format!("{}é{}", "a".repeat(max_message_len(NICK, "#test") - 1), "b".repeat(10));
+        harness.write("/#test/send", &format!("{}\n", text)).unwrap();
+
+        let first = harness.server.expect("PRIVMSG #test :");
+        let second = harness.server.expect("PRIVMSG #test :");
+        assert_eq!(first, format!("PRIVMSG #test :{}", "a".repeat(max_message_len(NICK, "#test") - 1)));
+        assert_eq!(second, format!("PRIVMSG #test :é{}", "b".repeat(10)));
+    }
+
+    #[test]
+    fn partial_lines_wait_for_the_rest() {
+        use fuse_mt::FilesystemMT;
+        use libc::O_WRONLY;
+        use std::path::Path;
+
+        let harness = joined("#test");
+        let path = Path::new("/#test/send");
+        let req = harness.req();
+
+        let (fh, _) = harness.fs.open(req, path, O_WRONLY as u32).unwrap();
+        harness.fs.write(req, path, fh, 0, b"hel".to_vec(), 0).unwrap();
+        assert_eq!(harness.read("/#test/send").unwrap(), "");
+        harness.fs.write(req, path, fh, 3, b"lo\nunfinished".to_vec(), 0).unwrap();
+        harness.server.expect("PRIVMSG #test :hello");
+
+        // Closing the file sends whatever is left
+        harness.fs.release(req, path, fh, 0, 0, true).unwrap();
+        harness.server.expect("PRIVMSG #test :unfinished");
+    }
+
+    #[test]
+    fn msg_command_opens_a_conversation() {
+        let harness = Harness::start();
+        harness.write("/send", "/msg Carol how are you\n").unwrap();
+
+        harness.server.expect("PRIVMSG Carol :how are you");
+        harness.wait_for_line("/carol/receive", &format!("{}: how are you", NICK));
+    }
+
+    #[test]
+    fn part_command_leaves() {
+        let harness = joined("#test");
+        harness.write("/send", "/part #test,#other bye,\n").unwrap();
+        harness.server.expect("PART #test :bye");
+        harness.server.expect("PART #other");
+
+        harness.server.send(&format!(":{}!user@localhost PART #test :bye", NICK));
+        harness.wait_for_line("/#test/receive", &format!("{} has left (bye)", NICK));
+    }
+
+    #[test]
+    fn errors_go_to_the_channel_they_concern() {
+        let harness = joined("#test");
+        harness.server.send(&format!(":irc.test 404 {} #test :Cannot send to channel", NICK));
+        harness.wait_for_line("/#test/receive", "Error: Cannot send to channel");
+
+        // Without a directory to go to, they go to the top level
+        harness.server.send(&format!(":irc.test 403 {} #nowhere :No such channel", NICK));
+        harness.wait_for_line("/receive", "Error: #nowhere: No such channel");
+        assert!(! harness.exists("/#nowhere"));
+    }
+
+    #[test]
+    fn whois_replies_become_files() {
+        let harness = Harness::start();
+        harness.write("/send", "/whois Alice,bob\n").unwrap();
+        harness.server.expect("WHOIS Alice");
+        harness.server.expect("WHOIS bob");
+
+        // Replies without a file of their own come in between the others
+        for line in &[
+            "311 {} Alice a example.net * :Alice A.",
+            "319 {} Alice :@#ops #chat",
+            "313 {} Alice :is an IRC operator",
+            "378 {} Alice :is connecting from *@example.net 192.0.2.1",
+            "312 {} Alice irc.test :Test server",
+            "671 {} Alice :is using a secure connection",
+            "330 {} Alice alice_account :is logged in as",
+            "318 {} Alice :End of /WHOIS list.",
+            "401 {} bob :No such nick/channel",
+            "318 {} bob :End of /WHOIS list.",
+        ] {
+            harness.server.send(&format!(":irc.test {}", line.replace("{}", NICK)));
+        }
+        harness.wait_for_line("/receive", "End of WHOIS for bob");
+
+        assert_eq!(harness.read("/whois/alice/user").unwrap(), "a\n");
+        assert_eq!(harness.read("/whois/alice/host").unwrap(), "example.net\n");
+        assert_eq!(harness.read("/whois/alice/realname").unwrap(), "Alice A.\n");
+        assert_eq!(harness.read("/whois/alice/server").unwrap(), "irc.test\n");
+        assert_eq!(harness.read("/whois/alice/channels").unwrap(), "@#ops\n#chat\n");
+        assert_eq!(harness.read("/whois/alice/account").unwrap(), "alice_account\n");
+        assert_eq!(harness.read("/whois/alice/idle").unwrap(), "");
+        assert!(! harness.exists("/whois/bob"));
+    }
+
+    #[test]
+    fn mode_lists_have_a_size_limit() {
+        use handles::LIST_LIMIT;
+        use libc::EFBIG;
+
+        let harness = joined("#test");
+        harness.wait_for_path("/#test/bans");
+
+        let masks = (0..LIST_LIMIT / 16).map(|i| format!("*!*@host{:06}.ex\n", i)).collect::<String>();
+        assert_eq!(harness.write("/#test/bans", &masks), Err(EFBIG));
+        assert_eq!(harness.read("/#test/bans").unwrap(), "");
+    }
+
+    #[test]
+    fn truncating_a_mode_list_removes_the_rest() {
+        let harness = joined("#test");
+        harness.wait_for_path("/#test/bans");
+        for mask in &["*!*@a.example", "*!*@b.example", "*!*@c.example"] {
+            harness.server.send(&format!(":irc.test 367 {} #test {} alice 0", NICK, mask));
+        }
+        harness.server.send(&format!(":irc.test 368 {} #test :End of channel ban list", NICK));
+        harness.wait_for_line("/#test/bans", "c.example");
+
+        let bans = harness.read("/#test/bans").unwrap();
+        let first = bans.find('\n').unwrap() as u64 + 1;
+        harness.fs.truncate(harness.req(), Path::new("/#test/bans"), None, first).unwrap();
+
+        let mut removed = vec![harness.server.expect("MODE #test -b "), harness.server.expect("MODE #test -b ")];
+        removed.sort();
+        assert_eq!(removed, vec!["MODE #test -b *!*@b.example", "MODE #test -b *!*@c.example"]);
+
+        // Until the server confirms it
+        assert_eq!(harness.read("/#test/bans").unwrap(), bans);
+    }
+
+    // A process that's in a group besides its primary one, and that group
+    struct GroupMember {
+        pid: u32,
+        gid: u32,
+        child: Option<Child>,
+    }
+
+    impl Drop for GroupMember {
+        fn drop(&mut self) {
+            if let Some(ref mut child) = self.child {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    fn groups_of(pid: u32) -> Vec<u32> {
+        let mut status = String::new();
+        let _ = File::open(format!("/proc/{}/status", pid)).and_then(|mut file| file.read_to_string(&mut status));
+        status.lines()
+            .find(|line| line.starts_with("Groups:"))
+            .map(|line| line["Groups:".len()..].split_whitespace().filter_map(|g| g.parse().ok()).collect())
+            .unwrap_or_else(Vec::new)
+    }
+
+    // This process if it has supplementary groups, otherwise (as root) a child
+    // that's given one
+    fn group_member() -> Option<GroupMember> {
+        if let Some(&gid) = groups_of(process::id()).first() {
+            return Some(GroupMember { pid: process::id(), gid: gid, child: None });
+        }
+        if unsafe { libc::getuid() } != 0 {
+            return None;
+        }
+
+        let gid: u32 = 4242;
+        let child = unsafe {
+            Command::new("sleep").arg("60")
+                .pre_exec(move || {
+                    if libc::setgroups(1, &gid) == 0 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
+                })
+                .spawn()
+                .ok()?
+        };
+
+        // Its groups only change once pre_exec has run
+        let member = GroupMember { pid: child.id(), gid: gid, child: Some(child) };
+        let deadline = Instant::now() + TIMEOUT;
+        while ! groups_of(member.pid).contains(&gid) {
+            if Instant::now() > deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Some(member)
+    }
+
+    #[test]
+    fn supplementary_groups_get_group_permissions() {
+        let mut harness = Harness::start();
+        let member = match group_member() {
+            Some(member) => member,
+            None => {
+                println!("skipping: no process with a supplementary group");
+                return;
+            },
+        };
+
+        harness.server.send(&format!(":irc.test NOTICE {} :*** for the group", NICK));
+        harness.wait_for_line("/receive", "for the group");
+
+        let owner = harness.req();
+        for &(path, mode) in &[("/", 0o750), ("/receive", 0o640)] {
+            harness.fs.chown(owner, Path::new(path), None, None, Some(member.gid)).unwrap();
+            harness.fs.chmod(owner, Path::new(path), None, mode).unwrap();
+        }
+
+        // Neither the owner nor in the group by its primary gid
+        harness.act_as(RequestInfo { unique: 0, uid: owner.uid + 1, gid: member.gid + 1, pid: member.pid });
+        assert!(harness.read("/receive").unwrap().contains("for the group"));
+        assert_eq!(harness.write("/send", "hello\n"), Err(EACCES));
+
+        harness.fs.chmod(owner, Path::new("/receive"), None, 0o600).unwrap();
+        assert_eq!(harness.read("/receive"), Err(EACCES));
+    }
+
+    #[test]
+    fn only_send_files_take_writes() {
+        let harness = Harness::start();
+        assert_eq!(harness.write("/queue", "hello\n"), Err(EINVAL));
+        assert!(harness.write("/receive", "hello\n").is_err());
+    }
 }
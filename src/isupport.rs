@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+
+use names::CaseMapping;
+use state::ModeChange;
+
+// What the server told us about itself in RPL_ISUPPORT (005). Tokens are
+// either KEY=VALUE, a bare KEY, or -KEY to withdraw an earlier one.
+#[derive(Debug, Default)]
+pub struct ISupport {
+    tokens: BTreeMap<String, Option<String>>,
+}
+
+// A token from one 005 reply
+pub enum Token {
+    Set(String, Option<String>),
+    Unset(String),
+}
+
+impl ISupport {
+    pub fn new() -> Self {
+        ISupport {
+            tokens: BTreeMap::new(),
+        }
+    }
+
+    // Takes the parameters of a 005 reply, minus our nickname and the trailing
+    // "are supported by this server", and returns the tokens in it
+    pub fn update<'a, I: IntoIterator<Item = &'a String>>(&mut self, params: I) -> Vec<Token> {
+        let mut changes = Vec::new();
+
+        for param in params {
+            if param.starts_with('-') {
+                let key = param[1..].to_owned();
+                self.tokens.remove(&key);
+                changes.push(Token::Unset(key));
+            } else {
+                let mut parts = param.splitn(2, '=');
+                let key = parts.next().unwrap_or("").to_owned();
+                if key.is_empty() {
+                    continue;
+                }
+                let value = parts.next().map(|v| v.to_owned());
+                self.tokens.insert(key.clone(), value.clone());
+                changes.push(Token::Set(key, value));
+            }
+        }
+
+        changes
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match self.tokens.get(key) {
+            Some(&Some(ref value)) => Some(value),
+            Some(&None) => Some(""),
+            None => None,
+        }
+    }
+
+    // Characters that start a channel name
+    pub fn chantypes(&self) -> &str {
+        self.get("CHANTYPES").unwrap_or("#&")
+    }
+
+    pub fn is_channel(&self, target: &str) -> bool {
+        target.chars().next().map(|c| self.chantypes().contains(c)).unwrap_or(false)
+    }
+
+    // (mode, prefix) pairs, e.g. ('o', '@'), from PREFIX=(ov)@+
+    pub fn prefix(&self) -> Vec<(char, char)> {
+        let prefix = self.get("PREFIX").unwrap_or("(ov)@+");
+
+        if !prefix.starts_with('(') {
+            return Vec::new();
+        }
+
+        match prefix[1..].find(')') {
+            Some(end) => {
+                let modes = &prefix[1..end + 1];
+                let prefixes = &prefix[end + 2..];
+                modes.chars().zip(prefixes.chars()).collect()
+            },
+            None => Vec::new(),
+        }
+    }
+
+    pub fn prefix_chars(&self) -> Vec<char> {
+        self.prefix().into_iter().map(|(_, prefix)| prefix).collect()
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.get("CASEMAPPING").and_then(CaseMapping::from_str).unwrap_or_default()
+    }
+
+    // The four groups of channel modes: lists, always take a parameter,
+    // take a parameter only when set, and never take one
+    pub fn chanmodes(&self) -> Vec<String> {
+        let mut groups = self.get("CHANMODES").unwrap_or("beI,k,l,imnpst")
+            .split(',')
+            .map(|group| group.to_owned())
+            .collect::<Vec<_>>();
+        groups.resize(4, String::new());
+        groups
+    }
+
+    // Modes that hold a list of masks, like +b
+    pub fn list_modes(&self) -> String {
+        self.chanmodes().swap_remove(0)
+    }
+
+    // Modes that give a member a status, like +o
+    pub fn status_modes(&self) -> Vec<char> {
+        self.prefix().into_iter().map(|(mode, _)| mode).collect()
+    }
+
+    // Splits a mode string such as "+nt-k key" into individual changes,
+    // taking parameters from params as each mode requires
+    pub fn parse_modes(&self, modes: &str, params: &[String]) -> Vec<ModeChange> {
+        let groups = self.chanmodes();
+        let status_modes = self.status_modes();
+        let mut params = params.iter();
+        let mut changes = Vec::new();
+        let mut set = true;
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => set = true,
+                '-' => set = false,
+                _ => {
+                    let takes_param = groups[0].contains(mode)
+                        || groups[1].contains(mode)
+                        || (set && groups[2].contains(mode))
+                        || status_modes.contains(&mode);
+                    let param = if takes_param {
+                        params.next().map(|p| p.to_owned())
+                    } else {
+                        None
+                    };
+
+                    changes.push(ModeChange {
+                        set: set,
+                        mode: mode,
+                        param: param,
+                    });
+                },
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn isupport(tokens: &[&str]) -> ISupport {
+        let mut isupport = ISupport::new();
+        isupport.update(&tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>());
+        isupport
+    }
+
+    // Tokens as they'd be written to /isupport: "KEY=VALUE", "KEY" or "-KEY"
+    fn describe(tokens: Vec<Token>) -> Vec<String> {
+        tokens.into_iter()
+            .map(|token| match token {
+                Token::Set(key, Some(value)) => format!("{}={}", key, value),
+                Token::Set(key, None) => key,
+                Token::Unset(key) => format!("-{}", key),
+            })
+            .collect()
+    }
+
+    fn modes(isupport: &ISupport, modes: &str, params: &[&str]) -> Vec<(bool, char, Option<String>)> {
+        let params = params.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        isupport.parse_modes(modes, &params).into_iter()
+            .map(|change| (change.set, change.mode, change.param))
+            .collect()
+    }
+
+    #[test]
+    fn tokens_are_set_and_unset() {
+        let mut isupport = ISupport::new();
+        let params = ["CHANTYPES=#", "EXCEPTS", "NETWORK=Example=Net", "=oops", ""]
+            .iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(describe(isupport.update(&params)), vec!["CHANTYPES=#", "EXCEPTS", "NETWORK=Example=Net"]);
+
+        assert_eq!(isupport.get("CHANTYPES"), Some("#"));
+        assert_eq!(isupport.get("EXCEPTS"), Some(""));
+        assert_eq!(isupport.get("NETWORK"), Some("Example=Net"));
+        assert_eq!(isupport.get("MISSING"), None);
+
+        // Later replies override or withdraw earlier ones
+        let params = ["CHANTYPES=#&", "-EXCEPTS", "-NEVERSET"]
+            .iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(describe(isupport.update(&params)), vec!["CHANTYPES=#&", "-EXCEPTS", "-NEVERSET"]);
+        assert_eq!(isupport.get("CHANTYPES"), Some("#&"));
+        assert_eq!(isupport.get("EXCEPTS"), None);
+        assert_eq!(isupport.get("NETWORK"), Some("Example=Net"));
+    }
+
+    #[test]
+    fn defaults_without_tokens() {
+        let isupport = ISupport::new();
+        assert_eq!(isupport.chantypes(), "#&");
+        assert!(isupport.is_channel("#a") && isupport.is_channel("&a"));
+        assert!(! isupport.is_channel("alice") && ! isupport.is_channel(""));
+        assert_eq!(isupport.prefix(), vec![('o', '@'), ('v', '+')]);
+        assert_eq!(isupport.casemapping(), CaseMapping::Rfc1459);
+        assert_eq!(isupport.chanmodes(), vec!["beI", "k", "l", "imnpst"]);
+        assert_eq!(isupport.list_modes(), "beI");
+    }
+
+    #[test]
+    fn channel_types_and_casemapping() {
+        let network = isupport(&["CHANTYPES=!", "CASEMAPPING=ascii"]);
+        assert!(network.is_channel("!a"));
+        assert!(! network.is_channel("#a"));
+        assert_eq!(network.casemapping(), CaseMapping::Ascii);
+
+        // Unknown mappings fall back to the default
+        assert_eq!(isupport(&["CASEMAPPING=rfc7613"]).casemapping(), CaseMapping::Rfc1459);
+    }
+
+    #[test]
+    fn prefixes() {
+        let prefix = |value: &str| isupport(&[value]).prefix();
+
+        assert_eq!(prefix("PREFIX=(qaohv)~&@%+"), vec![('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]);
+        assert_eq!(isupport(&["PREFIX=(ohv)@%+"]).prefix_chars(), vec!['@', '%', '+']);
+        assert_eq!(isupport(&["PREFIX=(ohv)@%+"]).status_modes(), vec!['o', 'h', 'v']);
+
+        // Extra modes or prefixes are ignored
+        assert_eq!(prefix("PREFIX=(ov)@"), vec![('o', '@')]);
+        assert_eq!(prefix("PREFIX=(o)@+"), vec![('o', '@')]);
+
+        // An empty or malformed PREFIX means there are none
+        assert!(prefix("PREFIX=").is_empty());
+        assert!(prefix("PREFIX").is_empty());
+        assert!(prefix("PREFIX=()").is_empty());
+        assert!(prefix("PREFIX=(ov@+").is_empty());
+        assert!(prefix("PREFIX=ov)@+").is_empty());
+        assert!(prefix("PREFIX=@+").is_empty());
+    }
+
+    #[test]
+    fn chanmodes_always_has_four_groups() {
+        let chanmodes = |value: &str| isupport(&[value]).chanmodes();
+
+        assert_eq!(chanmodes("CHANMODES=beI,k,l,imnpst"), vec!["beI", "k", "l", "imnpst"]);
+        assert_eq!(chanmodes("CHANMODES=b,k"), vec!["b", "k", "", ""]);
+        assert_eq!(chanmodes("CHANMODES="), vec!["", "", "", ""]);
+        assert_eq!(chanmodes("CHANMODES=b,k,l,imnt,XYZ"), vec!["b", "k", "l", "imnt"]);
+        assert_eq!(isupport(&["CHANMODES=eIb,k,l,imnt"]).list_modes(), "eIb");
+    }
+
+    #[test]
+    fn mode_parameters() {
+        let isupport = isupport(&["CHANMODES=beI,k,l,imnpst", "PREFIX=(ov)@+"]);
+
+        assert_eq!(modes(&isupport, "+nt", &[]), vec![(true, 'n', None), (true, 't', None)]);
+
+        // Lists, keys and statuses take one either way, limits only when set
+        assert_eq!(
+            modes(&isupport, "+bkl-lbo+v", &["*!*@a", "key", "10", "*!*@b", "alice", "bob"]),
+            vec![
+                (true, 'b', Some("*!*@a".to_owned())),
+                (true, 'k', Some("key".to_owned())),
+                (true, 'l', Some("10".to_owned())),
+                (false, 'l', None),
+                (false, 'b', Some("*!*@b".to_owned())),
+                (false, 'o', Some("alice".to_owned())),
+                (true, 'v', Some("bob".to_owned())),
+            ],
+        );
+
+        // Running out of parameters leaves the rest without any
+        assert_eq!(
+            modes(&isupport, "+ob", &["alice"]),
+            vec![(true, 'o', Some("alice".to_owned())), (true, 'b', None)],
+        );
+
+        // Modes it doesn't know about take none, and no sign means +
+        assert_eq!(
+            modes(&isupport, "Xk", &["key", "spare"]),
+            vec![(true, 'X', None), (true, 'k', Some("key".to_owned()))],
+        );
+    }
+}
@@ -1,5 +1,6 @@
 extern crate serde;
 #[macro_use] extern crate serde_derive;
+extern crate serde_json;
 
 extern crate fuse_mt;
 use fuse_mt::FuseMT;
@@ -15,6 +16,7 @@ use irc::client::prelude::Config;
 
 extern crate toml;
 extern crate time;
+extern crate openssl;
 extern crate libc;
 extern crate num_cpus;
 
@@ -31,8 +33,27 @@ use ircfs::*;
 pub mod config;
 use config::*;
 
+pub mod connection;
+pub mod proxy;
+
+pub mod acl;
+pub mod attributes;
 pub mod filesystem;
+pub mod handles;
+pub mod isupport;
+pub mod names;
+pub mod numerics;
 pub mod permissions;
+pub mod queue;
+pub mod state;
+pub mod transport;
+
+#[cfg(test)]
+mod benches;
+#[cfg(test)]
+mod fake_server;
+#[cfg(test)]
+mod testing;
 
 fn is_valid_u16(n: &OsStr) -> Result<(), OsString> {
     let n = n.to_string_lossy();
@@ -97,13 +118,16 @@ fn main() {
         .arg(Arg::with_name("ssl")
              .help("Connect via SSL")
              .long("ssl"))
+        .arg(Arg::with_name("allow_other")
+             .help("Let other users access the filesystem (requires user_allow_other in /etc/fuse.conf)")
+             .long("allow-other"))
         // .arg(Arg::with_name("daemonize")
         //      .short("d")
         //      .long("daemonize"))
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
 
-    let mut config: Config = {
+    let server_config: ServerConfig = {
         match matches.value_of_os("config") {
             Some(path) => {
                 match File::open(path) {
@@ -112,7 +136,7 @@ fn main() {
                         let _ = file.read_to_string(&mut buf);
                         match toml::from_str(&buf) {
                             Ok(config) => {
-                                convert_config(config)
+                                config
                             },
                             Err(e) => {
                                 let _ = writeln!(stderr(),
@@ -136,6 +160,15 @@ fn main() {
         }
     };
 
+    let mut fs_config = match fs_config(&server_config) {
+        Ok(fs_config) => fs_config,
+        Err(e) => {
+            let _ = writeln!(stderr(), "Error in config file: {}", e);
+            exit(1);
+        },
+    };
+    let mut config: Config = convert_config(server_config);
+
     let nickname = matches
         .value_of_os("nickname").map(|s| s.to_owned())
         .or(var_os("USER"));
@@ -174,6 +207,9 @@ fn main() {
             .map(|s| s.to_string_lossy().into_owned());
     }
     config.use_ssl = Some(matches.is_present("ssl"));
+    if matches.is_present("allow_other") {
+        fs_config.allow_other = true;
+    }
 
     let num_threads = num_cpus::get();
 
@@ -192,12 +228,18 @@ fn main() {
     }
 
     let uid = unsafe { libc::getuid() };
-    let gid = unsafe { libc::getgid() };
+    let gid = fs_config.group.unwrap_or(unsafe { libc::getgid() });
+
+    let mut mount_options = Vec::new();
+    if fs_config.allow_other {
+        mount_options.push(OsStr::new("-o"));
+        mount_options.push(OsStr::new("allow_other"));
+    }
 
-    match IrcFs::new(&config, uid, gid){
+    match IrcFs::new(&config, &fs_config, uid, gid){
         Ok(filesystem) => {
             let fuse_mt = FuseMT::new(filesystem, num_threads);
-            if let Err(e) = fuse_mt::mount(fuse_mt, &mountpoint, &[]) {
+            if let Err(e) = fuse_mt::mount(fuse_mt, &mountpoint, &mount_options) {
                 let _ = writeln!(stderr(), "Failed to mout filesystem: {}", e);
                 exit(1);
             }
@@ -0,0 +1,236 @@
+use std::path::{Path, PathBuf};
+
+// Names at the top level that belong to ircfs rather than to a channel
+pub const RESERVED_NAMES: &'static [&'static str] = &[
+    "connection",
+    "isupport",
+    "queue",
+    "raw",
+    "receive",
+    "send",
+    "whois",
+];
+
+// How the server compares channel names and nicknames, from the CASEMAPPING
+// token of RPL_ISUPPORT. Names that are equal under the server's case mapping
+// share a directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseMapping {
+    Ascii,
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl Default for CaseMapping {
+    // What servers use if they don't say otherwise
+    fn default() -> Self {
+        CaseMapping::Rfc1459
+    }
+}
+
+impl CaseMapping {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match &*s.to_lowercase() {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    pub fn fold(&self, name: &str) -> String {
+        name.chars()
+            .map(|c| match (*self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+                (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+                (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+                (CaseMapping::Rfc1459, '~') => '^',
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+// Names that can't be given a directory at all
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c| c == '\0' || c == '\r' || c == '\n')
+}
+
+// Turns a name into something that's safe to use as a single path component:
+// "%" and "/" are percent-encoded, as are "." and ".." and the first character
+// of reserved names, so that a channel can't escape or shadow anything.
+pub fn encode_name(name: &str) -> String {
+    let mut encoded = String::new();
+
+    let escape_all = name == "." || name == "..";
+    let escape_first = RESERVED_NAMES.contains(&name);
+
+    for (i, c) in name.chars().enumerate() {
+        if escape_all || (escape_first && i == 0) || c == '%' || c == '/' {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+
+    encoded
+}
+
+// The reverse of encode_name. Malformed escapes are left as they are.
+pub fn decode_name(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let (high, low) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2]));
+            if let (Some(high), Some(low)) = (high, low) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+// The directory for a channel or query
+pub fn channel_path(casemapping: CaseMapping, name: &str) -> Option<PathBuf> {
+    if is_valid_name(name) {
+        Some(Path::new("/").join(encode_name(&casemapping.fold(name))))
+    } else {
+        None
+    }
+}
+
+// Whether a path could have come from channel_path
+pub fn is_channel_dir(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+
+    path.parent() == Some(Path::new("/"))
+        && !RESERVED_NAMES.contains(&&*name)
+        && name != "."
+        && name != ".."
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_escapes_what_it_has_to() {
+        assert_eq!(encode_name("#ircfs"), "#ircfs");
+        assert_eq!(encode_name("#a/b"), "#a%2Fb");
+        assert_eq!(encode_name("100%"), "100%25");
+        assert_eq!(encode_name("é/ü"), "é%2Fü");
+
+        assert_eq!(encode_name("."), "%2E");
+        assert_eq!(encode_name(".."), "%2E%2E");
+        assert_eq!(encode_name("..."), "...");
+        assert_eq!(encode_name(".hidden"), ".hidden");
+
+        // Only the first character, and only of names that are exactly reserved
+        assert_eq!(encode_name("send"), "%73end");
+        assert_eq!(encode_name("whois"), "%77hois");
+        assert_eq!(encode_name("#send"), "#send");
+        assert_eq!(encode_name("sender"), "sender");
+    }
+
+    #[test]
+    fn encoded_names_decode_to_the_original() {
+        let mut names = vec![
+            "#ircfs", "#a/b", "/", "//", "%", "%%", "%41", "%2F", "100%", "a%2", "%zz",
+            ".", "..", "...", "./..", "é/ü", "\u{1F600}%", "#Foo[1]",
+        ];
+        names.extend(RESERVED_NAMES);
+
+        for name in names {
+            let encoded = encode_name(name);
+            assert!(! encoded.contains('/'), "{:?} -> {:?}", name, encoded);
+            assert!(encoded != "." && encoded != "..", "{:?} -> {:?}", name, encoded);
+            assert!(! RESERVED_NAMES.contains(&&*encoded), "{:?} -> {:?}", name, encoded);
+            assert_eq!(decode_name(&encoded), name);
+        }
+    }
+
+    #[test]
+    fn malformed_escapes_are_left_alone() {
+        assert_eq!(decode_name("%"), "%");
+        assert_eq!(decode_name("a%"), "a%");
+        assert_eq!(decode_name("%4"), "%4");
+        assert_eq!(decode_name("ab%4"), "ab%4");
+        assert_eq!(decode_name("%zz"), "%zz");
+        assert_eq!(decode_name("%4g"), "%4g");
+        assert_eq!(decode_name("%%41"), "%A");
+        assert_eq!(decode_name("%é"), "%é");
+
+        // Lowercase hex is fine, and bytes that aren't UTF-8 are replaced
+        assert_eq!(decode_name("%2f"), "/");
+        assert_eq!(decode_name("%FF"), "\u{FFFD}");
+        assert_eq!(decode_name("%C3%A9"), "é");
+    }
+
+    #[test]
+    fn case_folding() {
+        let name = "#Foo[]\\~Ä";
+        assert_eq!(CaseMapping::Ascii.fold(name), "#foo[]\\~Ä");
+        assert_eq!(CaseMapping::Rfc1459.fold(name), "#foo{}|^Ä");
+        assert_eq!(CaseMapping::StrictRfc1459.fold(name), "#foo{}|~Ä");
+
+        for &casemapping in &[CaseMapping::Ascii, CaseMapping::Rfc1459, CaseMapping::StrictRfc1459] {
+            let folded = casemapping.fold(name);
+            assert_eq!(casemapping.fold(&folded), folded);
+        }
+    }
+
+    #[test]
+    fn casemapping_names() {
+        assert_eq!(CaseMapping::from_str("ascii"), Some(CaseMapping::Ascii));
+        assert_eq!(CaseMapping::from_str("RFC1459"), Some(CaseMapping::Rfc1459));
+        assert_eq!(CaseMapping::from_str("strict-rfc1459"), Some(CaseMapping::StrictRfc1459));
+        assert_eq!(CaseMapping::from_str("rfc7613"), None);
+        assert_eq!(CaseMapping::default(), CaseMapping::Rfc1459);
+    }
+
+    #[test]
+    fn channel_paths() {
+        assert_eq!(channel_path(CaseMapping::Rfc1459, "#Foo[1]"), Some(PathBuf::from("/#foo{1}")));
+        assert_eq!(channel_path(CaseMapping::Ascii, "#Foo[1]"), Some(PathBuf::from("/#foo[1]")));
+        assert_eq!(channel_path(CaseMapping::Ascii, "Send"), Some(PathBuf::from("/%73end")));
+        assert_eq!(channel_path(CaseMapping::Ascii, ".."), Some(PathBuf::from("/%2E%2E")));
+        assert_eq!(channel_path(CaseMapping::Ascii, ""), None);
+        assert_eq!(channel_path(CaseMapping::Ascii, "#a\r\n"), None);
+        assert_eq!(channel_path(CaseMapping::Ascii, "#a\0"), None);
+
+        for name in &["#Foo[1]", "Send", "..", "#a/b"] {
+            assert!(is_channel_dir(&channel_path(CaseMapping::Rfc1459, name).unwrap()), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn channel_dirs() {
+        assert!(is_channel_dir(Path::new("/#a")));
+        assert!(is_channel_dir(Path::new("/%73end")));
+        assert!(! is_channel_dir(Path::new("/send")));
+        assert!(! is_channel_dir(Path::new("/whois")));
+        assert!(! is_channel_dir(Path::new("/#a/b")));
+        assert!(! is_channel_dir(Path::new("/")));
+        assert!(! is_channel_dir(Path::new("#a")));
+    }
+}
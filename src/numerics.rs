@@ -0,0 +1,235 @@
+use irc::client::prelude::*;
+
+use time::{self, Timespec};
+
+use std::path::{Path, PathBuf};
+
+use names::encode_name;
+
+// Where a numeric reply should be shown, and how
+pub struct Route {
+    // The channel or nick whose directory the reply belongs in
+    pub target: String,
+    pub text: String,
+}
+
+// The numeric code of a reply, whether or not the irc crate knows it by name,
+// along with its parameters (minus our own nickname) and trailing text
+pub fn numeric(command: &Command) -> Option<(u16, &[String], Option<&str>)> {
+    let (code, args, suffix) = match *command {
+        Command::Response(response, ref args, ref suffix) => (response as u16, args, suffix),
+        Command::Raw(ref code, ref args, ref suffix) => match code.parse::<u16>() {
+            Ok(code) if code >= 1 && code <= 999 => (code, args, suffix),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let args = if args.is_empty() { &args[..] } else { &args[1..] };
+    Some((code, args, suffix.as_ref().map(|s| s.as_str())))
+}
+
+// Routes replies that are about a particular nick or channel to its directory.
+// Anything else is left for the top-level receive file.
+pub fn route(code: u16, args: &[String], suffix: Option<&str>) -> Option<Route> {
+    let arg = |i: usize| args.get(i).map(|a| a.as_str()).unwrap_or("");
+    let suffix = suffix.unwrap_or("");
+
+    let (target, text) = match code {
+        // RPL_WHOISUSER
+        311 => (arg(0), format!("{} is {}@{} ({})", arg(0), arg(1), arg(2), suffix)),
+        // RPL_WHOISSERVER
+        312 => (arg(0), format!("{} is connected to {} ({})", arg(0), arg(1), suffix)),
+        // RPL_WHOISOPERATOR
+        313 => (arg(0), format!("{} {}", arg(0), suffix)),
+        // RPL_WHOWASUSER
+        314 => (arg(0), format!("{} was {}@{} ({})", arg(0), arg(1), arg(2), suffix)),
+        // RPL_ENDOFWHO
+        315 => (arg(0), format!("End of WHO for {}", arg(0))),
+        // RPL_WHOISIDLE
+        317 => {
+            let idle = arg(1).parse::<u64>().map(format_duration).unwrap_or(arg(1).to_owned());
+            let text = match arg(2).parse::<i64>() {
+                Ok(signon) => format!("{} has been idle for {}, signed on {}",
+                    arg(0),
+                    idle,
+                    time::at(Timespec::new(signon, 0)).strftime("%F %T").unwrap(),
+                ),
+                Err(_) => format!("{} has been idle for {}", arg(0), idle),
+            };
+            (arg(0), text)
+        },
+        // RPL_ENDOFWHOIS
+        318 => (arg(0), format!("End of WHOIS for {}", arg(0))),
+        // RPL_WHOISCHANNELS
+        319 => (arg(0), format!("{} is in {}", arg(0), suffix.trim())),
+        // RPL_WHOISACCOUNT
+        330 => (arg(0), format!("{} is logged in as {}", arg(0), arg(1))),
+        // ERR_NOSUCHNICK, ERR_NOSUCHCHANNEL
+        401 | 403 => (arg(0), format!("Error: {}: {}", arg(0), suffix)),
+        // ERR_CANNOTSENDTOCHAN, ERR_NOTONCHANNEL, ERR_CHANOPRIVSNEEDED and
+        // the reasons a JOIN can fail
+        404 | 442 | 482 | 471 | 473 | 474 | 475 => (arg(0), format!("Error: {}", suffix)),
+        _ => return None,
+    };
+
+    if target.is_empty() {
+        None
+    } else {
+        Some(Route {
+            target: target.to_owned(),
+            text: text,
+        })
+    }
+}
+
+// What the WHOIS replies for one nick have told us so far
+#[derive(Debug, Default)]
+pub struct Whois {
+    user: Option<String>,
+    host: Option<String>,
+    realname: Option<String>,
+    server: Option<String>,
+    channels: Vec<String>,
+    idle: Option<String>,
+    account: Option<String>,
+}
+
+// The WHOIS replies that Whois keeps something from
+const WHOIS_FIELDS: &'static [u16] = &[311, 312, 317, 319, 330];
+
+impl Whois {
+    pub fn is_field(code: u16) -> bool {
+        WHOIS_FIELDS.contains(&code)
+    }
+
+    // Takes in a WHOIS reply (minus our nickname). Anything is_field doesn't
+    // accept is ignored.
+    pub fn update(&mut self, code: u16, args: &[String], suffix: Option<&str>) {
+        let arg = |i: usize| args.get(i).map(|a| a.to_owned());
+
+        match code {
+            // RPL_WHOISUSER
+            311 => {
+                self.user = arg(1);
+                self.host = arg(2);
+                self.realname = suffix.map(|s| s.to_owned());
+            },
+            // RPL_WHOISSERVER
+            312 => self.server = arg(1),
+            // RPL_WHOISIDLE
+            317 => self.idle = arg(1),
+            // RPL_WHOISCHANNELS, which can be split over several replies
+            319 => self.channels.extend(
+                suffix.unwrap_or("").split(' ').filter(|c| !c.is_empty()).map(|c| c.to_owned())
+            ),
+            // RPL_WHOISACCOUNT
+            330 => self.account = arg(1),
+            _ => {},
+        }
+    }
+
+    // Only set if the nick exists
+    pub fn found(&self) -> bool {
+        self.user.is_some()
+    }
+
+    // The files of the nick's WHOIS directory and their contents. Anything
+    // the server didn't tell us (like an account, for a user who isn't
+    // logged in) is left empty.
+    pub fn files(&self) -> Vec<(&'static str, String)> {
+        let line = |value: &Option<String>| match *value {
+            Some(ref value) => format!("{}\n", value),
+            None => String::new(),
+        };
+
+        vec![
+            ("user", line(&self.user)),
+            ("host", line(&self.host)),
+            ("realname", line(&self.realname)),
+            ("server", line(&self.server)),
+            ("channels", self.channels.iter().map(|c| format!("{}\n", c)).collect()),
+            ("idle", line(&self.idle)),
+            ("account", line(&self.account)),
+        ]
+    }
+}
+
+// Where the WHOIS files for a (case-folded) nick go
+pub fn whois_path(nick: &str) -> PathBuf {
+    Path::new("/whois").join(encode_name(nick))
+}
+
+// e.g. "1h 2m 3s"
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes, seconds) =
+        (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+
+    let mut parts = Vec::new();
+    if days > 0 {
+        parts.push(format!("{}d", days));
+    }
+    if hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    if minutes > 0 {
+        parts.push(format!("{}m", minutes));
+    }
+    if seconds > 0 || parts.is_empty() {
+        parts.push(format!("{}s", seconds));
+    }
+
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn file(whois: &Whois, name: &str) -> String {
+        whois.files().into_iter().find(|&(n, _)| n == name).unwrap().1
+    }
+
+    #[test]
+    fn whois_keeps_the_fields_it_knows() {
+        let mut whois = Whois::default();
+        assert!(! whois.found());
+
+        whois.update(311, &args(&["alice", "a", "example.net", "*"]), Some("Alice A."));
+        whois.update(313, &args(&["alice"]), Some("is an IRC operator"));
+        whois.update(319, &args(&["alice"]), Some("@#ops #chat "));
+        whois.update(319, &args(&["alice"]), Some("#more"));
+        whois.update(671, &args(&["alice"]), Some("is using a secure connection"));
+        whois.update(330, &args(&["alice", "alice_account"]), Some("is logged in as"));
+
+        assert!(whois.found());
+        assert_eq!(file(&whois, "user"), "a\n");
+        assert_eq!(file(&whois, "host"), "example.net\n");
+        assert_eq!(file(&whois, "realname"), "Alice A.\n");
+        assert_eq!(file(&whois, "channels"), "@#ops\n#chat\n#more\n");
+        assert_eq!(file(&whois, "account"), "alice_account\n");
+        assert_eq!(file(&whois, "server"), "");
+    }
+
+    #[test]
+    fn whois_fields() {
+        for &code in &[311, 312, 317, 319, 330] {
+            assert!(Whois::is_field(code), "{}", code);
+        }
+        for &code in &[301, 313, 318, 338, 378, 401, 671] {
+            assert!(! Whois::is_field(code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(90061), "1d 1h 1m 1s");
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A message waiting to be sent
+pub struct Outgoing {
+    pub target: String,
+    pub channel_path: PathBuf,
+    pub text: String,
+}
+
+// Outgoing messages are rate limited with a token bucket: up to `burst`
+// messages can be sent at once, after which one more may be sent every
+// `window / burst` seconds. Targets with pending messages take turns, so
+// pasting a large file into one channel doesn't hold up the others.
+pub struct OutQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    burst: u32,
+    window: Duration,
+}
+
+struct QueueState {
+    // Targets with pending messages, in the order they'll be served
+    targets: VecDeque<String>,
+    pending: HashMap<String, VecDeque<Outgoing>>,
+}
+
+impl OutQueue {
+    pub fn new(burst: u32, window: Duration) -> Self {
+        OutQueue {
+            state: Mutex::new(QueueState {
+                targets: VecDeque::new(),
+                pending: HashMap::new(),
+            }),
+            ready: Condvar::new(),
+            burst: if burst == 0 { 1 } else { burst },
+            window: window,
+        }
+    }
+
+    pub fn push(&self, outgoing: Outgoing) {
+        let mut state = self.state.lock().unwrap();
+
+        if ! state.pending.contains_key(&outgoing.target) {
+            state.targets.push_back(outgoing.target.clone());
+        }
+        state.pending.entry(outgoing.target.clone())
+            .or_insert_with(VecDeque::new)
+            .push_back(outgoing);
+
+        self.ready.notify_one();
+    }
+
+    // Discards every pending message, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = state.pending.values().map(|messages| messages.len()).sum();
+        state.targets.clear();
+        state.pending.clear();
+        count
+    }
+
+    // Contents of the "queue" status file: one "target message" line per pending message
+    pub fn listing(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut listing = String::new();
+
+        for target in &state.targets {
+            if let Some(messages) = state.pending.get(target) {
+                for outgoing in messages {
+                    listing.push_str(&format!("{} {}\n", target, outgoing.text));
+                }
+            }
+        }
+
+        listing
+    }
+
+    // Spawns the thread that sends messages as the rate limit allows
+    pub fn start<F>(queue: Arc<Self>, mut send: F)
+    where F: FnMut(Outgoing) + Send + 'static {
+        thread::spawn(move || {
+            let capacity = queue.burst as f64;
+            let window = queue.window.as_secs() as f64
+                + queue.window.subsec_nanos() as f64 / 1_000_000_000.0;
+            let rate = if window > 0.0 { capacity / window } else { ::std::f64::INFINITY };
+
+            let mut tokens = capacity;
+            let mut last_refill = Instant::now();
+
+            loop {
+                let outgoing = {
+                    let mut state = queue.state.lock().unwrap();
+                    while state.targets.is_empty() {
+                        state = queue.ready.wait(state).unwrap();
+                    }
+
+                    let elapsed = last_refill.elapsed();
+                    let elapsed = elapsed.as_secs() as f64
+                        + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+                    tokens = (tokens + elapsed * rate).min(capacity);
+                    last_refill = Instant::now();
+
+                    if tokens < 1.0 {
+                        let wait = (1.0 - tokens) / rate;
+                        drop(state);
+                        thread::sleep(Duration::from_millis((wait * 1000.0).ceil() as u64));
+                        continue;
+                    }
+
+                    tokens -= 1.0;
+                    state.pop_next()
+                };
+
+                if let Some(outgoing) = outgoing {
+                    send(outgoing);
+                }
+            }
+        });
+    }
+}
+
+impl QueueState {
+    fn pop_next(&mut self) -> Option<Outgoing> {
+        let target = match self.targets.pop_front() {
+            Some(target) => target,
+            None => return None,
+        };
+
+        let (outgoing, more) = match self.pending.get_mut(&target) {
+            Some(messages) => (messages.pop_front(), ! messages.is_empty()),
+            None => (None, false),
+        };
+
+        if more {
+            self.targets.push_back(target);
+        } else {
+            self.pending.remove(&target);
+        }
+
+        outgoing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn outgoing(target: &str, text: &str) -> Outgoing {
+        Outgoing {
+            target: target.to_owned(),
+            channel_path: PathBuf::from(format!("/{}", target)),
+            text: text.to_owned(),
+        }
+    }
+
+    fn texts(state: &mut QueueState) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Some(outgoing) = state.pop_next() {
+            texts.push(outgoing.text);
+        }
+        texts
+    }
+
+    #[test]
+    fn targets_take_turns() {
+        let queue = OutQueue::new(5, Duration::from_secs(10));
+        for text in &["a1", "a2", "a3"] {
+            queue.push(outgoing("#a", text));
+        }
+        queue.push(outgoing("#b", "b1"));
+        queue.push(outgoing("#c", "c1"));
+        queue.push(outgoing("#b", "b2"));
+
+        let mut state = queue.state.lock().unwrap();
+        assert_eq!(texts(&mut state), vec!["a1", "b1", "c1", "a2", "b2", "a3"]);
+        assert!(state.targets.is_empty());
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn a_target_that_empties_goes_to_the_back() {
+        let queue = OutQueue::new(5, Duration::from_secs(10));
+        queue.push(outgoing("#a", "a1"));
+        queue.push(outgoing("#b", "b1"));
+
+        let mut state = queue.state.lock().unwrap();
+        assert_eq!(state.pop_next().unwrap().text, "a1");
+        drop(state);
+        queue.push(outgoing("#a", "a2"));
+
+        let mut state = queue.state.lock().unwrap();
+        assert_eq!(texts(&mut state), vec!["b1", "a2"]);
+        assert!(state.pop_next().is_none());
+    }
+
+    #[test]
+    fn listing_and_clearing() {
+        let queue = OutQueue::new(5, Duration::from_secs(10));
+        assert_eq!(queue.listing(), "");
+        assert_eq!(queue.clear(), 0);
+
+        queue.push(outgoing("#a", "one"));
+        queue.push(outgoing("bob", "two"));
+        queue.push(outgoing("#a", "three"));
+        assert_eq!(queue.listing(), "#a one\n#a three\nbob two\n");
+
+        assert_eq!(queue.clear(), 3);
+        assert_eq!(queue.listing(), "");
+        assert!(queue.state.lock().unwrap().pop_next().is_none());
+
+        queue.push(outgoing("bob", "four"));
+        assert_eq!(queue.listing(), "bob four\n");
+    }
+
+    #[test]
+    fn sending_is_rate_limited() {
+        // Two at once, then one every half second
+        let queue = Arc::new(OutQueue::new(2, Duration::from_secs(1)));
+        let (tx, rx) = mpsc::channel();
+        OutQueue::start(queue.clone(), move |outgoing| {
+            tx.send((Instant::now(), outgoing.text)).unwrap();
+        });
+
+        let start = Instant::now();
+        for text in &["1", "2", "3", "4"] {
+            queue.push(outgoing("#a", text));
+        }
+        let sent = (0..4).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+        assert_eq!(sent.iter().map(|&(_, ref text)| text.as_str()).collect::<Vec<_>>(), vec!["1", "2", "3", "4"]);
+
+        let after = |i: usize| sent[i].0.duration_since(start);
+        assert!(after(1) < Duration::from_millis(250), "{:?}", after(1));
+        assert!(after(2) >= Duration::from_millis(450), "{:?}", after(2));
+        assert!(after(3) >= Duration::from_millis(950), "{:?}", after(3));
+        assert!(queue.listing().is_empty());
+
+        // The bucket refills while nothing is being sent
+        thread::sleep(Duration::from_millis(1100));
+        let start = Instant::now();
+        queue.push(outgoing("#a", "5"));
+        queue.push(outgoing("#b", "6"));
+        for _ in 0..2 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(250), "{:?}", start.elapsed());
+    }
+}
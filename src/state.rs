@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub struct ServerState {
+    channels: HashMap<String, BTreeSet<String>>,
+    users: HashMap<String, User>,
+    modes: HashMap<String, ChannelModes>,
+}
+
+// The modes of a channel, apart from its members' status (+o, +v etc.)
+#[derive(Debug, Clone, Default)]
+pub struct ChannelModes {
+    // Modes that are set, along with their parameter if they have one
+    settings: BTreeMap<char, Option<String>>,
+    // The masks in each list mode, like +b
+    lists: BTreeMap<char, Vec<String>>,
+}
+
+// One mode being set or unset, e.g. +b nick!*@*
+#[derive(Debug, Clone)]
+pub struct ModeChange {
+    pub set: bool,
+    pub mode: char,
+    pub param: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct User {
+    pub account: Option<String>,
+    pub away: Option<String>,
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        ServerState {
+            channels: HashMap::new(),
+            users: HashMap::new(),
+            modes: HashMap::new(),
+        }
+    }
+
+    // Called when we join a channel, so that stale membership is discarded
+    pub fn reset_channel(&mut self, channel: &str) {
+        self.modes.remove(channel);
+        if let Some(nicks) = self.channels.insert(channel.to_owned(), BTreeSet::new()) {
+            for nick in nicks {
+                self.forget_if_unseen(&nick);
+            }
+        }
+    }
+
+    // `account` is None if the server didn't tell us (no extended-join),
+    // and Some(None) if the user is known not to be logged in
+    pub fn join(&mut self, channel: &str, nick: &str, account: Option<Option<String>>) {
+        self.channels.entry(channel.to_owned())
+            .or_insert_with(BTreeSet::new)
+            .insert(nick.to_owned());
+
+        let user = self.users.entry(nick.to_owned()).or_insert_with(User::default);
+        if let Some(account) = account {
+            user.account = account;
+        }
+    }
+
+    // `prefixes` are the status prefixes (e.g. '@') that may appear in front
+    // of the nicknames, from the server's PREFIX token
+    pub fn names<'a, I: IntoIterator<Item = &'a str>>(&mut self, channel: &str, nicks: I, prefixes: &[char]) {
+        for nick in nicks {
+            let nick = nick.trim_start_matches(prefixes);
+            if nick.is_empty() {
+                continue;
+            }
+
+            self.channels.entry(channel.to_owned())
+                .or_insert_with(BTreeSet::new)
+                .insert(nick.to_owned());
+            self.users.entry(nick.to_owned()).or_insert_with(User::default);
+        }
+    }
+
+    pub fn part(&mut self, channel: &str, nick: &str) {
+        if let Some(nicks) = self.channels.get_mut(channel) {
+            nicks.remove(nick);
+        }
+        self.forget_if_unseen(nick);
+    }
+
+    pub fn leave_channel(&mut self, channel: &str) {
+        self.modes.remove(channel);
+        if let Some(nicks) = self.channels.remove(channel) {
+            for nick in nicks {
+                self.forget_if_unseen(&nick);
+            }
+        }
+    }
+
+    // Returns the channels that the user was in
+    pub fn quit(&mut self, nick: &str) -> Vec<String> {
+        let channels = self.channels_of(nick);
+        for channel in &channels {
+            if let Some(nicks) = self.channels.get_mut(channel) {
+                nicks.remove(nick);
+            }
+        }
+        self.users.remove(nick);
+        channels
+    }
+
+    // Returns the channels that the user is in
+    pub fn rename(&mut self, old: &str, new: &str) -> Vec<String> {
+        let channels = self.channels_of(old);
+        for channel in &channels {
+            if let Some(nicks) = self.channels.get_mut(channel) {
+                nicks.remove(old);
+                nicks.insert(new.to_owned());
+            }
+        }
+        if let Some(user) = self.users.remove(old) {
+            self.users.insert(new.to_owned(), user);
+        }
+        channels
+    }
+
+    // Returns the channels that the user is in
+    pub fn set_account(&mut self, nick: &str, account: Option<String>) -> Vec<String> {
+        if let Some(user) = self.users.get_mut(nick) {
+            user.account = account;
+        }
+        self.channels_of(nick)
+    }
+
+    // Returns the channels that the user is in
+    pub fn set_away(&mut self, nick: &str, away: Option<String>) -> Vec<String> {
+        if let Some(user) = self.users.get_mut(nick) {
+            user.away = away;
+        }
+        self.channels_of(nick)
+    }
+
+    pub fn channels_of(&self, nick: &str) -> Vec<String> {
+        self.channels.iter()
+            .filter(|&(_, nicks)| nicks.contains(nick))
+            .map(|(channel, _)| channel.to_owned())
+            .collect()
+    }
+
+    // Contents of a channel's "users" file: one "nick account [away]" line per user
+    pub fn user_listing(&self, channel: &str) -> String {
+        let mut listing = String::new();
+
+        if let Some(nicks) = self.channels.get(channel) {
+            for nick in nicks {
+                let user = self.users.get(nick).cloned().unwrap_or_default();
+                listing.push_str(nick);
+                listing.push(' ');
+                listing.push_str(user.account.as_ref().map(|a| a.as_str()).unwrap_or("*"));
+                if let Some(ref message) = user.away {
+                    listing.push_str(&format!(" (away: {})", message));
+                }
+                listing.push('\n');
+            }
+        }
+
+        listing
+    }
+
+    // `list_modes` are the modes that hold a list of masks (e.g. "beI"). Status
+    // modes should already have been filtered out.
+    pub fn apply_modes(&mut self, channel: &str, changes: &[ModeChange], list_modes: &str) {
+        let modes = self.modes.entry(channel.to_owned()).or_insert_with(ChannelModes::default);
+
+        for change in changes {
+            if list_modes.contains(change.mode) {
+                if let Some(ref mask) = change.param {
+                    let list = modes.lists.entry(change.mode).or_insert_with(Vec::new);
+                    list.retain(|m| m != mask);
+                    if change.set {
+                        list.push(mask.to_owned());
+                    }
+                }
+            } else if change.set {
+                modes.settings.insert(change.mode, change.param.clone());
+            } else {
+                modes.settings.remove(&change.mode);
+            }
+        }
+    }
+
+    // From RPL_CHANNELMODEIS, which lists every mode that's set
+    pub fn set_modes(&mut self, channel: &str, changes: &[ModeChange]) {
+        let modes = self.modes.entry(channel.to_owned()).or_insert_with(ChannelModes::default);
+
+        modes.settings = changes.iter()
+            .filter(|change| change.set)
+            .map(|change| (change.mode, change.param.clone()))
+            .collect();
+    }
+
+    // From the replies to a list query like "MODE #channel +b"
+    pub fn set_list(&mut self, channel: &str, mode: char, masks: Vec<String>) {
+        self.modes.entry(channel.to_owned())
+            .or_insert_with(ChannelModes::default)
+            .lists.insert(mode, masks);
+    }
+
+    // Contents of a channel's "modes" file, e.g. "+ntl 50"
+    pub fn mode_listing(&self, channel: &str) -> String {
+        let settings = match self.modes.get(channel) {
+            Some(modes) => &modes.settings,
+            None => return String::new(),
+        };
+
+        let mut letters = String::from("+");
+        let mut params = Vec::new();
+        for (mode, param) in settings {
+            letters.push(*mode);
+            if let Some(ref param) = *param {
+                params.push(param.as_str());
+            }
+        }
+
+        if params.is_empty() {
+            format!("{}\n", letters)
+        } else {
+            format!("{} {}\n", letters, params.join(" "))
+        }
+    }
+
+    // Contents of a list file like "bans": one mask per line
+    pub fn list_listing(&self, channel: &str, mode: char) -> String {
+        let mut listing = String::new();
+
+        if let Some(list) = self.modes.get(channel).and_then(|modes| modes.lists.get(&mode)) {
+            for mask in list {
+                listing.push_str(mask);
+                listing.push('\n');
+            }
+        }
+
+        listing
+    }
+
+    fn forget_if_unseen(&mut self, nick: &str) {
+        if self.channels.values().all(|nicks| !nicks.contains(nick)) {
+            self.users.remove(nick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(channel: &str, nicks: &[(&str, Option<&str>)]) -> ServerState {
+        let mut state = ServerState::new();
+        state.reset_channel(channel);
+        for &(nick, account) in nicks {
+            state.join(channel, nick, Some(account.map(|a| a.to_owned())));
+        }
+        state
+    }
+
+    #[test]
+    fn join_with_and_without_an_account() {
+        let mut state = ServerState::new();
+        state.join("#a", "alice", Some(Some("alice_account".to_owned())));
+        state.join("#a", "bob", Some(None));
+        state.join("#a", "carol", None);
+        assert_eq!(state.user_listing("#a"), "alice alice_account\nbob *\ncarol *\n");
+
+        // Without extended-join, a later JOIN says nothing about the account
+        state.join("#b", "alice", None);
+        assert_eq!(state.user_listing("#b"), "alice alice_account\n");
+
+        // With it, logging out shows up as "*"
+        state.join("#c", "alice", Some(None));
+        assert_eq!(state.user_listing("#a"), "alice *\nbob *\ncarol *\n");
+    }
+
+    #[test]
+    fn names_strip_status_prefixes() {
+        let mut state = ServerState::new();
+        state.names("#a", "@alice +bob @+carol dave ".split(' '), &['@', '+']);
+        assert_eq!(state.user_listing("#a"), "alice *\nbob *\ncarol *\ndave *\n");
+    }
+
+    #[test]
+    fn set_away_and_account() {
+        let mut state = joined("#a", &[("alice", None), ("bob", None)]);
+        state.join("#b", "alice", None);
+
+        let mut channels = state.set_away("alice", Some("lunch".to_owned()));
+        channels.sort();
+        assert_eq!(channels, vec!["#a", "#b"]);
+        assert_eq!(state.user_listing("#a"), "alice * (away: lunch)\nbob *\n");
+
+        assert_eq!(state.set_account("alice", Some("alice_account".to_owned())).len(), 2);
+        assert_eq!(state.user_listing("#b"), "alice alice_account (away: lunch)\n");
+
+        state.set_away("alice", None);
+        state.set_account("alice", None);
+        assert_eq!(state.user_listing("#b"), "alice *\n");
+
+        // Nobody we share a channel with
+        assert!(state.set_away("nobody", Some("gone".to_owned())).is_empty());
+        assert!(state.set_account("nobody", None).is_empty());
+    }
+
+    #[test]
+    fn rename_keeps_the_user() {
+        let mut state = joined("#a", &[("alice", Some("acct")), ("bob", None)]);
+        state.join("#b", "alice", None);
+        state.set_away("alice", Some("brb".to_owned()));
+
+        let mut channels = state.rename("alice", "alicia");
+        channels.sort();
+        assert_eq!(channels, vec!["#a", "#b"]);
+        assert_eq!(state.user_listing("#a"), "alicia acct (away: brb)\nbob *\n");
+        assert_eq!(state.user_listing("#b"), "alicia acct (away: brb)\n");
+        assert!(state.channels_of("alice").is_empty());
+    }
+
+    #[test]
+    fn quit_leaves_every_channel() {
+        let mut state = joined("#a", &[("alice", Some("acct")), ("bob", None)]);
+        state.join("#b", "alice", None);
+
+        let mut channels = state.quit("alice");
+        channels.sort();
+        assert_eq!(channels, vec!["#a", "#b"]);
+        assert_eq!(state.user_listing("#a"), "bob *\n");
+        assert_eq!(state.user_listing("#b"), "");
+
+        // Coming back, nothing is remembered
+        state.join("#a", "alice", None);
+        assert_eq!(state.user_listing("#a"), "alice *\nbob *\n");
+    }
+
+    #[test]
+    fn users_are_forgotten_once_no_channel_has_them() {
+        let mut state = joined("#a", &[("alice", Some("acct"))]);
+        state.join("#b", "alice", None);
+
+        // Still in #b, so the account is kept
+        state.part("#a", "alice");
+        state.join("#a", "alice", None);
+        assert_eq!(state.user_listing("#a"), "alice acct\n");
+
+        state.part("#a", "alice");
+        state.leave_channel("#b");
+        state.join("#a", "alice", None);
+        assert_eq!(state.user_listing("#a"), "alice *\n");
+
+        // Rejoining a channel starts its membership afresh
+        state.reset_channel("#a");
+        assert_eq!(state.user_listing("#a"), "");
+        state.join("#a", "bob", None);
+        assert_eq!(state.user_listing("#a"), "bob *\n");
+        assert!(state.channels_of("alice").is_empty());
+    }
+}
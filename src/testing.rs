@@ -0,0 +1,163 @@
+// Drives an IrcFs connected to a FakeServer through its FilesystemMT methods,
+// the way FUSE would, without mounting anything
+
+use fuse_mt::{FilesystemMT, RequestInfo};
+use irc::client::prelude::Config;
+use libc::{self, c_int, O_RDONLY, O_WRONLY};
+
+use std::env::temp_dir;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{FsConfig, TlsConfig};
+use fake_server::{FakeServer, TIMEOUT};
+use ircfs::IrcFs;
+
+pub const NICK: &'static str = "ircfs";
+
+// Keeps each harness's attributes file apart from the others'
+static HARNESSES: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Harness {
+    pub server: FakeServer,
+    pub fs: IrcFs,
+    req: RequestInfo,
+}
+
+impl Harness {
+    // Connects a new filesystem to a new fake server and registers it
+    pub fn start() -> Harness {
+        let harness = Harness::connect();
+        harness.server.register(NICK);
+        harness
+    }
+
+    // Leaves registration to the test
+    pub fn connect() -> Harness {
+        let server = FakeServer::start();
+
+        let config = Config {
+            nickname: Some(NICK.to_owned()),
+            username: Some(NICK.to_owned()),
+            realname: Some(NICK.to_owned()),
+            server: Some("127.0.0.1".to_owned()),
+            port: Some(server.port()),
+            use_ssl: Some(false),
+            ..Config::default()
+        };
+        let attributes_file = temp_dir().join(format!(
+            "ircfs-test-{}-{}.toml", process::id(), HARNESSES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let fs_config = FsConfig {
+            tls: TlsConfig::default(),
+            proxy: None,
+            truncate_receive: false,
+            file_mode: 0o600,
+            dir_mode: 0o700,
+            group: None,
+            attributes_file: Some(attributes_file),
+            allow_other: false,
+            acl: Vec::new(),
+        };
+
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let fs = IrcFs::new(&config, &fs_config, uid, gid).unwrap();
+
+        Harness {
+            server: server,
+            fs: fs,
+            req: RequestInfo { unique: 0, uid: uid, gid: gid, pid: process::id() },
+        }
+    }
+
+    pub fn req(&self) -> RequestInfo {
+        self.req
+    }
+
+    // Makes the calls that follow on behalf of someone else
+    pub fn act_as(&mut self, req: RequestInfo) {
+        self.req = req;
+    }
+
+    // Like `printf data >> path`: open, write, then close
+    pub fn write(&self, path: &str, data: &str) -> Result<(), c_int> {
+        let path = Path::new(path);
+        let (fh, _) = self.fs.open(self.req, path, O_WRONLY as u32)?;
+        let written = self.fs.write(self.req, path, fh, 0, data.as_bytes().to_vec(), 0);
+        self.fs.flush(self.req, path, fh, 0)?;
+        self.fs.release(self.req, path, fh, 0, 0, true)?;
+        written.map(|_| ())
+    }
+
+    // Like `cat path`
+    pub fn read(&self, path: &str) -> Result<String, c_int> {
+        let path = Path::new(path);
+        let (fh, _) = self.fs.open(self.req, path, O_RDONLY as u32)?;
+
+        let mut contents = Vec::new();
+        loop {
+            match self.fs.read(self.req, path, fh, contents.len() as u64, 4096) {
+                Ok(ref data) if data.is_empty() => break,
+                Ok(data) => contents.extend(data),
+                Err(e) => {
+                    self.fs.release(self.req, path, fh, 0, 0, false)?;
+                    return Err(e);
+                },
+            }
+        }
+
+        self.fs.release(self.req, path, fh, 0, 0, false)?;
+        Ok(String::from_utf8(contents).unwrap())
+    }
+
+    // Like `ls -a path`, sorted
+    pub fn list(&self, path: &str) -> Result<Vec<String>, c_int> {
+        let path = Path::new(path);
+        let (fh, _) = self.fs.opendir(self.req, path, 0)?;
+        let entries = self.fs.readdir(self.req, path, fh);
+        self.fs.releasedir(self.req, path, fh, 0)?;
+
+        let mut names = entries?.into_iter()
+            .map(|entry| entry.name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.fs.getattr(self.req, Path::new(path), None).is_ok()
+    }
+
+    // Messages from the server are applied to the tree on another thread, so
+    // this waits for a file to have a line containing text, and returns it
+    pub fn wait_for_line(&self, path: &str, text: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let contents = self.read(path).unwrap_or_default();
+            if let Some(line) = contents.lines().find(|line| line.contains(text)) {
+                return line.to_owned();
+            }
+            assert!(Instant::now() < deadline, "{} never got a line with {:?}; it has:\n{}", path, text, contents);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Likewise for files and directories that are created in the background
+    pub fn wait_for_path(&self, path: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while ! self.exists(path) {
+            assert!(Instant::now() < deadline, "{} was never created", path);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // And for a channel's directory with everything in it
+    pub fn wait_for_channel(&self, channel: &str) {
+        for name in &["receive", "send", "users", "messages.jsonl"] {
+            self.wait_for_path(&format!("/{}/{}", channel, name));
+        }
+    }
+}
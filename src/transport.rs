@@ -0,0 +1,48 @@
+use irc::client::prelude::*;
+use irc::error::Result as IrcResult;
+
+// The connection IrcFs talks to the network through. The filesystem logic
+// only goes through this trait, so anything that can exchange IRC messages
+// (a test double, another client library, one of several servers) can stand
+// in for the irc crate's IrcServer.
+pub trait Transport: Send + Sync {
+    fn send(&self, message: Message) -> IrcResult<()>;
+
+    fn current_nickname(&self) -> &str;
+
+    // Registers with the server (NICK/USER etc.)
+    fn identify(&self) -> IrcResult<()>;
+
+    // Calls handler with every message received, until the connection ends
+    fn for_each_incoming(&self, handler: &mut FnMut(Message)) -> IrcResult<()>;
+
+    fn send_privmsg(&self, target: &str, text: &str) -> IrcResult<()> {
+        self.send(Command::PRIVMSG(target.to_owned(), text.to_owned()).into())
+    }
+
+    fn send_join(&self, channels: &str) -> IrcResult<()> {
+        self.send(Command::JOIN(channels.to_owned(), None, None).into())
+    }
+
+    fn send_join_with_keys(&self, channels: &str, keys: &str) -> IrcResult<()> {
+        self.send(Command::JOIN(channels.to_owned(), Some(keys.to_owned()), None).into())
+    }
+}
+
+impl Transport for IrcServer {
+    fn send(&self, message: Message) -> IrcResult<()> {
+        Server::send(self, message)
+    }
+
+    fn current_nickname(&self) -> &str {
+        IrcServer::current_nickname(self)
+    }
+
+    fn identify(&self) -> IrcResult<()> {
+        ServerExt::identify(self)
+    }
+
+    fn for_each_incoming(&self, handler: &mut FnMut(Message)) -> IrcResult<()> {
+        Server::for_each_incoming(self, |message| handler(message))
+    }
+}
@@ -0,0 +1,233 @@
+// Mounts ircfs for real against a fake IRC server and uses it through the
+// kernel, the way a shell would. These are skipped (and pass) where FUSE isn't
+// available, e.g. in most containers.
+
+#[path = "../src/fake_server.rs"]
+mod fake_server;
+
+use std::env::temp_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fake_server::{FakeServer, TIMEOUT};
+
+const NICK: &'static str = "ircfs";
+
+static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+
+struct Mount {
+    server: FakeServer,
+    dir: PathBuf,
+    child: Child,
+}
+
+impl Mount {
+    // None if this system can't mount FUSE filesystems
+    fn start() -> Option<Mount> {
+        if ! Path::new("/dev/fuse").exists() {
+            println!("skipping: /dev/fuse is unavailable");
+            return None;
+        }
+
+        let server = FakeServer::start();
+        let n = MOUNTS.fetch_add(1, Ordering::SeqCst);
+        let dir = temp_dir().join(format!("ircfs-mount-{}-{}", process::id(), n));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Only to keep chmod and chown from writing to the real attributes file
+        let config = dir.with_extension("toml");
+        File::create(&config).unwrap().write_all(format!(
+            "attributes_file = {:?}\n", dir.with_extension("attributes.toml")
+        ).as_bytes()).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ircfs"))
+            .arg("-s").arg("127.0.0.1")
+            .arg("-p").arg(server.port().to_string())
+            .arg("-n").arg(NICK)
+            .arg("-c").arg(&config)
+            .arg(&dir)
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let deadline = Instant::now() + TIMEOUT;
+        while ! dir.join("send").exists() {
+            if let Ok(Some(status)) = child.try_wait() {
+                println!("skipping: ircfs couldn't mount ({})", status);
+                return None;
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                panic!("ircfs never mounted");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        server.register(NICK);
+
+        Some(Mount {
+            server: server,
+            dir: dir,
+            child: child,
+        })
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    // Like `echo line >> path`
+    fn echo(&self, path: &str, line: &str) {
+        let mut file = OpenOptions::new().append(true).open(self.path(path)).unwrap();
+        file.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    }
+
+    // Waits for a file to have a line containing text
+    fn wait_for_line(&self, path: &str, text: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let contents = fs::read_to_string(self.path(path)).unwrap_or_default();
+            if let Some(line) = contents.lines().find(|line| line.contains(text)) {
+                return line.to_owned();
+            }
+            assert!(Instant::now() < deadline, "{} never got a line with {:?}; it has:\n{}", path, text, contents);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn wait_for_path(&self, path: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while ! self.path(path).exists() {
+            assert!(Instant::now() < deadline, "{} was never created", path);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn join(&self, channel: &str) {
+        self.echo("send", &format!("/join {}", channel));
+        self.server.expect(&format!("JOIN {}", channel));
+        self.server.send(&format!(":{}!user@localhost JOIN {}", NICK, channel));
+        self.wait_for_line(&format!("{}/receive", channel), "has joined");
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let unmounted = Command::new("fusermount").arg("-u").arg(&self.dir)
+            .status().map(|status| status.success()).unwrap_or(false);
+        if ! unmounted {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+        let _ = fs::remove_dir(&self.dir);
+        let _ = fs::remove_file(self.dir.with_extension("toml"));
+        let _ = fs::remove_file(self.dir.with_extension("attributes.toml"));
+    }
+}
+
+fn names(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn top_level() {
+    let mount = match Mount::start() {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    assert_eq!(
+        names(&mount.dir),
+        vec!["connection", "isupport", "queue", "raw", "receive", "send", "whois"],
+    );
+    assert!(fs::read_to_string(mount.path("connection")).unwrap().contains("tls: no"));
+
+    mount.server.send(&format!(":irc.test NOTICE {} :*** Looking up your hostname", NICK));
+    mount.wait_for_line("receive", "Looking up your hostname");
+}
+
+#[test]
+fn joining_and_parting() {
+    let mount = match Mount::start() {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    mount.join("#test");
+    mount.wait_for_path("#test/modes");
+    assert!(mount.path("#test").is_dir());
+    for name in &["messages.jsonl", "receive", "send", "users"] {
+        assert!(names(&mount.path("#test")).contains(&name.to_string()));
+    }
+
+    mount.echo("send", "/part #test");
+    mount.server.expect("PART #test");
+    mount.server.send(&format!(":{}!user@localhost PART #test", NICK));
+    mount.wait_for_line("#test/receive", "has left");
+}
+
+#[test]
+fn channels_as_directories() {
+    let mount = match Mount::start() {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    fs::create_dir(mount.path("#test")).unwrap();
+    mount.server.expect("JOIN #test");
+    mount.server.send(&format!(":{}!user@localhost JOIN #test", NICK));
+    mount.wait_for_line("#test/receive", "has joined");
+    assert_eq!(fs::create_dir(mount.path("#test")).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert!(fs::create_dir(mount.path("#test/sub")).is_err());
+
+    fs::remove_dir(mount.path("#test")).unwrap();
+    mount.server.expect("PART #test");
+    assert!(! mount.path("#test").exists());
+    assert!(fs::remove_dir(mount.path("whois")).is_err());
+}
+
+#[test]
+fn talking_in_a_channel() {
+    let mount = match Mount::start() {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    mount.join("#test");
+    mount.server.send(":alice!a@localhost PRIVMSG #test :hello there");
+    mount.wait_for_line("#test/receive", "alice: hello there");
+    mount.wait_for_line("#test/messages.jsonl", "hello there");
+
+    mount.echo("#test/send", "hi alice");
+    mount.server.expect("PRIVMSG #test :hi alice");
+    mount.wait_for_line("#test/receive", &format!("{}: hi alice", NICK));
+    assert_eq!(fs::read_to_string(mount.path("#test/send")).unwrap(), "hi alice\n");
+}
+
+#[test]
+fn permissions() {
+    let mount = match Mount::start() {
+        Some(mount) => mount,
+        None => return,
+    };
+
+    mount.join("#test");
+    let mode = |path: &str| fs::metadata(mount.path(path)).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode("#test"), 0o700);
+    assert_eq!(mode("#test/send"), 0o600);
+    assert_eq!(mode("#test/receive"), 0o400);
+    assert!(OpenOptions::new().append(true).open(mount.path("#test/receive")).is_err());
+
+    fs::set_permissions(mount.path("#test/send"), fs::Permissions::from_mode(0o640)).unwrap();
+    assert_eq!(mode("#test/send"), 0o640);
+}